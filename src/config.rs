@@ -1,14 +1,127 @@
-use std::process::Command;
+use std::{
+	borrow::Cow,
+	collections::{HashMap, HashSet},
+	path::{Path, PathBuf},
+	process::Command,
+	sync::OnceLock,
+};
 
 use color_eyre::eyre::{Result, WrapErr as _, bail};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
 use serde::{Deserialize, Deserializer};
 use v_utils::macros::{MyConfigPrimitives, Settings};
 
-#[derive(Clone, Debug, MyConfigPrimitives, Settings)]
+#[derive(Clone, Debug, JsonSchema, MyConfigPrimitives, Settings)]
 pub struct AppConfig {
 	pub quotes: Vec<Quote>,
-	pub balance: Option<Balance>,
-	pub text_padding: Option<u32>,
+	pub balance: Option<Balances>,
+	pub text_padding: Option<Padding>,
+	pub selection_mode: Option<SelectionMode>,
+	/// Quote lines past this are dropped, with an ellipsis marking the cut
+	pub quote_max_lines: Option<usize>,
+	/// Extra directories to load fonts from, on top of the system ones
+	pub font_dirs: Option<Vec<PathBuf>>,
+	/// Quote pools active during specific hours; `quotes` is used outside all of them
+	pub pools: Option<Vec<QuotePool>>,
+	/// Have Circle render the overlay before setting anything, instead of flashing the plain image first
+	pub circle_immediate: Option<bool>,
+	/// Distance between consecutive text lines, in multiples of the font size
+	pub line_spacing: Option<f32>,
+	/// When set, `generate` puts the overlay over a random photo from here instead of the vision document
+	pub generate_background_dir: Option<PathBuf>,
+	/// Treat byte-identical images under different names as one when cycling
+	pub dedupe_by_hash: Option<bool>,
+	/// Any SVG/CSS color. Defaults to white, or to the `--theme` one.
+	pub text_color: Option<String>,
+	/// Backdrop behind the text block, e.g. `"rgba(0, 0, 0, 0.4)"`. None by default, or the `--theme` one.
+	pub scrim_color: Option<String>,
+	/// Displays narrower than this (typically portrait ones) don't constrain where text can go
+	pub safe_area_min_width: Option<u32>,
+	/// Output names (e.g. a dock's phantom `"DVI-I-1"`) left out of the safe area and display resolution entirely
+	pub ignore_outputs: Option<Vec<String>>,
+	/// 0.0-1.0, for all overlay text. The per-element ones below take precedence.
+	pub text_opacity: Option<f32>,
+	pub quote_opacity: Option<f32>,
+	pub author_opacity: Option<f32>,
+	pub balance_opacity: Option<f32>,
+	/// A Circle arriving within this many ms of the previous one is ignored (keybind chatter). 0 disables.
+	pub circle_debounce_ms: Option<u64>,
+	/// Spaces a tab in quote/balance text expands to
+	pub tab_width: Option<usize>,
+	/// Where `--also-lockscreen` puts a copy of the generated wallpaper
+	pub lockscreen_image_path: Option<PathBuf>,
+	pub output_format: Option<OutputFormat>,
+	/// 1-100. Only applies when `output_format = "jpeg"`; png is lossless and ignores it.
+	pub jpeg_quality: Option<u8>,
+	/// With `output_format = "webp"`: lossless keeps text edges crisp, lossy makes much smaller files. Lossless by default;
+	/// lossy needs the `lossy-webp` feature.
+	pub webp_lossless: Option<bool>,
+	/// 0-100, for lossy webp only
+	pub webp_quality: Option<f32>,
+	/// Command setting the wallpaper, `{path}` being replaced by the image, e.g. `"swaymsg output * background {path} fill"`.
+	/// Split on whitespace, not run through a shell.
+	pub set_command: Option<String>,
+	/// Added to every channel of the background before compositing, -255..255
+	pub brightness: Option<i32>,
+	/// Contrast change of the background in percent; negative lowers it
+	pub contrast: Option<f32>,
+	/// JSON array of quotes (strings or `{text, author, tags}` objects, as quote APIs export them), added to `quotes`
+	pub quotes_json: Option<PathBuf>,
+	/// Clockwise rotation of the overlay, for outputs the compositor rotates: 0, 90, 180, 270, or `"auto"` to follow the outputs' `transform`
+	pub overlay_rotation: Option<OverlayRotation>,
+	/// Further TOML configs merged into this one, relative to its directory: their quotes, pools, font dirs and sources are appended,
+	/// other fields only fill in what this one leaves unset
+	pub include: Option<Vec<PathBuf>>,
+	/// What a run does when another one still holds the lock
+	pub lock_policy: Option<LockPolicy>,
+	/// Distinct quotes per wallpaper, up to 4: the first takes the top-right corner as usual, the others the remaining corners
+	pub quotes_per_wallpaper: Option<usize>,
+	/// Written into the output's metadata (PNG `pHYs`, JPEG JFIF density); none by default
+	pub output_dpi: Option<u32>,
+	/// Stylistic preset applied to the background after resizing, before brightness/contrast
+	pub image_filter: Option<ImageFilter>,
+	/// strftime format, e.g. `"%Y-%m-%d %H:%M"`, of a small line under the balances. It's the generation time, not a live
+	/// clock; the daemon's interval is what keeps it fresh.
+	pub timestamp: Option<String>,
+	/// Logo/avatar blended into a corner of the safe area
+	pub overlay_image: Option<OverlayImage>,
+	/// Drop exact-duplicate quotes (same text) from each pool, instead of only warning about them
+	pub dedupe_quotes: Option<bool>,
+	/// Most of the safe area's height (0.0-1.0) the main text block may take; fonts shrink until it fits
+	pub max_overlay_fraction: Option<f32>,
+	/// Only put the scrim behind the text when the image under it is too bright or busy to read it otherwise
+	pub auto_scrim: Option<bool>,
+	/// Run after every successful wallpaper set, e.g. `"wal -n -i {path}"`; split like `set_command`. Doesn't hold up the
+	/// swap, and its failure is only logged.
+	pub post_set_hook: Option<String>,
+	/// Quotes fetched over HTTP when one is picked (JSON array, TOML with a `quotes` array, or one per line), appended to `quotes`.
+	/// The last successful fetch is cached for when offline. Needs the `remote-quotes` feature.
+	pub quotes_url: Option<String>,
+	/// Show the image's file name (underscores as spaces) as a small caption, for collections named like `Artist - Title.jpg`
+	pub caption_from_filename: Option<bool>,
+	/// Regex run on the file name (sans extension) for `caption_from_filename`; its first capture group, or else the whole
+	/// match, is the caption. Names it doesn't match get no caption.
+	pub caption_pattern: Option<String>,
+	/// Used to scale the background to the display. `lanczos3` looks best; `triangle`/`nearest` are much faster, e.g. for Circle.
+	pub resize_filter: Option<ResizeFilter>,
+	/// Folders `circle --random` picks from when not given a directory: one by weight, then an image in it
+	pub sources: Option<Vec<BackgroundSource>>,
+	/// Wrap the quote text in typographic quotation marks
+	pub quote_marks: Option<bool>,
+	/// The opening and closing mark for `quote_marks`, e.g. `"«»"`; `"“”"` by default
+	pub quote_mark_chars: Option<String>,
+	/// How long `circle --blacklist` keeps an image out of rotation
+	pub blacklist_hours: Option<u64>,
+	/// Smooth the overlay's glyph edges. Turning it off gets crisper (if jaggier) small text on low-DPI screens, or a pixel-art look.
+	pub text_antialiasing: Option<bool>,
+	/// Same for the scrim, boxes and other shapes
+	pub shape_antialiasing: Option<bool>,
+	/// Render the text layer at this fraction (0.1-1) of the display's resolution and scale it up while compositing.
+	/// Saves memory and time on huge displays, at some cost in sharpness. 1 by default.
+	pub overlay_render_scale: Option<f32>,
+	/// Move the main text block into whichever rule-of-thirds cell of the safe area has the least detail (sky, negative space),
+	/// lining it up with that cell's side of the safe area, instead of always the top-right corner
+	pub thirds_placement: Option<bool>,
 }
 
 impl Default for AppConfig {
@@ -16,20 +129,877 @@ impl Default for AppConfig {
 		Self {
 			quotes: Vec::new(),
 			balance: None,
-			text_padding: Some(15),
+			text_padding: Some(Padding::Pixels(15)),
+			selection_mode: Some(SelectionMode::Random),
+			quote_max_lines: None,
+			font_dirs: None,
+			pools: None,
+			circle_immediate: Some(false),
+			line_spacing: Some(1.2),
+			generate_background_dir: None,
+			dedupe_by_hash: Some(false),
+			text_color: None,
+			scrim_color: None,
+			safe_area_min_width: None,
+			ignore_outputs: None,
+			text_opacity: Some(1.0),
+			quote_opacity: None,
+			author_opacity: None,
+			balance_opacity: None,
+			circle_debounce_ms: Some(100),
+			tab_width: Some(4),
+			lockscreen_image_path: None,
+			output_format: Some(OutputFormat::Png),
+			jpeg_quality: Some(90),
+			webp_lossless: Some(true),
+			webp_quality: Some(90.0),
+			set_command: None,
+			brightness: None,
+			contrast: None,
+			quotes_json: None,
+			overlay_rotation: None,
+			include: None,
+			lock_policy: Some(LockPolicy::Kill),
+			quotes_per_wallpaper: Some(1),
+			output_dpi: None,
+			image_filter: None,
+			timestamp: None,
+			overlay_image: None,
+			dedupe_quotes: Some(false),
+			max_overlay_fraction: None,
+			auto_scrim: Some(false),
+			post_set_hook: None,
+			quotes_url: None,
+			caption_from_filename: Some(false),
+			caption_pattern: None,
+			resize_filter: Some(ResizeFilter::Lanczos3),
+			sources: None,
+			quote_marks: Some(false),
+			quote_mark_chars: None,
+			blacklist_hours: Some(24),
+			text_antialiasing: Some(true),
+			shape_antialiasing: Some(true),
+			overlay_render_scale: Some(1.0),
+			thirds_placement: Some(false),
 		}
 	}
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/// Expands a leading `~` to `$HOME`, and `$VAR`/`${VAR}` anywhere. Unset variables are left as written.
+pub fn expand_path(path: &Path) -> PathBuf {
+	let Some(s) = path.to_str() else {
+		return path.to_path_buf();
+	};
+	let home = std::env::var("HOME").ok();
+	let s = match (s.strip_prefix('~'), &home) {
+		(Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => format!("{home}{rest}"),
+		_ => s.to_owned(),
+	};
+
+	let mut expanded = String::with_capacity(s.len());
+	let mut rest = s.as_str();
+	while let Some(dollar) = rest.find('$') {
+		expanded.push_str(&rest[..dollar]);
+		let after = &rest[dollar + 1..];
+		let (name, remainder) = match after.strip_prefix('{') {
+			Some(braced) => match braced.find('}') {
+				Some(end) => (&braced[..end], &braced[end + 1..]),
+				None => ("", after),
+			},
+			None => {
+				let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+				(&after[..end], &after[end..])
+			}
+		};
+		match std::env::var(name) {
+			Ok(value) if !name.is_empty() => expanded.push_str(&value),
+			_ => expanded.push_str(&rest[dollar..rest.len() - remainder.len()]),
+		}
+		rest = remainder;
+	}
+	expanded.push_str(rest);
+	PathBuf::from(expanded)
+}
+
+/// Warns about quotes whose text appears more than once (they'd come up more often), dropping all but the first if `dedupe`.
+fn check_duplicate_quotes(quotes: &mut Vec<Quote>, pool: &str, dedupe: bool) {
+	let mut seen = HashSet::new();
+	let mut duplicates = Vec::new();
+	for quote in quotes.iter() {
+		if !seen.insert(quote.text.as_str()) && !duplicates.contains(&quote.text) {
+			duplicates.push(quote.text.clone());
+		}
+	}
+	if duplicates.is_empty() {
+		return;
+	}
+
+	let listed = duplicates.iter().map(|text| format!("  {text:?}")).collect::<Vec<_>>().join("\n");
+	v_utils::elog!("Warning: duplicate quotes in {pool}{}:\n{listed}", if dedupe { " (keeping only the first of each)" } else { "" });
+	if dedupe {
+		let mut kept = HashSet::new();
+		quotes.retain(|quote| kept.insert(quote.text.clone()));
+	}
+}
+
+/// Quotes from `quotes_url`, refreshing the cached copy when the fetch works and falling back to it when it doesn't. Never
+/// fails: at worst there are no remote quotes this time.
+fn remote_quotes(url: &str) -> Vec<Quote> {
+	let cache_path = xdg::BaseDirectories::with_prefix("wallpaper_carousel").place_cache_file("quotes_url").ok();
+	let body = match fetch_quotes(url) {
+		Ok(body) => {
+			if let Some(path) = &cache_path
+				&& let Err(e) = std::fs::write(path, &body)
+			{
+				v_utils::elog!("Warning: failed to cache quotes_url at {}: {e}", path.display());
+			}
+			body
+		}
+		Err(e) => {
+			v_utils::elog!("Warning: failed to fetch quotes_url ({e}), using the cached copy");
+			match cache_path.as_deref().and_then(|path| std::fs::read_to_string(path).ok()) {
+				Some(body) => body,
+				None => {
+					v_utils::elog!("Warning: no cached copy of quotes_url either, going without its quotes");
+					return Vec::new();
+				}
+			}
+		}
+	};
+	parse_quotes_text(&body)
+}
+
+#[cfg(feature = "remote-quotes")]
+fn fetch_quotes(url: &str) -> Result<String> {
+	// Generation waits on this, so a slow network must not hold it up for long
+	let agent = ureq::AgentBuilder::new().timeout(std::time::Duration::from_secs(5)).build();
+	Ok(agent.get(url).call()?.into_string()?)
+}
+
+#[cfg(not(feature = "remote-quotes"))]
+fn fetch_quotes(_url: &str) -> Result<String> {
+	bail!("built without the `remote-quotes` feature")
+}
+
+/// A JSON array of quotes, a TOML document with a `quotes` array, or else one plain quote per non-empty line.
+fn parse_quotes_text(body: &str) -> Vec<Quote> {
+	if let Ok(quotes) = serde_json::from_str(body) {
+		return quotes;
+	}
+	let toml = ::config::Config::builder().add_source(::config::File::from_str(body, ::config::FileFormat::Toml)).build();
+	if let Ok(quotes) = toml.and_then(|toml| toml.get::<Vec<Quote>>("quotes")) {
+		return quotes;
+	}
+	body.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(|text| Quote {
+			text: text.to_owned(),
+			author: None,
+			source: None,
+			color: None,
+			tags: Vec::new(),
+		})
+		.collect()
+}
+
+/// Top-level keys of the config file at `path` that aren't fields of [`AppConfig`], sorted. Empty for formats the `config` crate can't read.
+fn unknown_keys(path: &Path) -> Vec<String> {
+	let schema = schemars::schema_for!(AppConfig);
+	let known: HashSet<&str> = schema
+		.get("properties")
+		.and_then(|properties| properties.as_object())
+		.map(|properties| properties.keys().map(String::as_str).collect())
+		.unwrap_or_default();
+	let builder = ::config::Config::builder();
+	let builder = match path.extension().is_some_and(|ext| ext == "nix") {
+		true => match path.to_str().and_then(|path| AppConfig::eval_nix_file(path).ok()) {
+			Some(json) => builder.add_source(::config::File::from_str(&json, ::config::FileFormat::Json)),
+			None => return Vec::new(),
+		},
+		false => builder.add_source(::config::File::from(path)),
+	};
+	let Ok(table) = builder.build().and_then(::config::Config::try_deserialize::<HashMap<String, ::config::Value>>) else {
+		return Vec::new();
+	};
+	let mut unknown: Vec<String> = table.into_keys().filter(|key| !known.contains(key.as_str())).collect();
+	unknown.sort();
+	unknown
+}
+
+/// Unknown keys are most likely typos, which would otherwise silently fall back to defaults. Only fatal with `strict`, so
+/// configs written for a newer version still load; without it [`AppConfig::try_build`] warns about each of them.
+fn deny_unknown_keys(path: &Path) -> Result<()> {
+	let unknown = unknown_keys(path);
+	if !unknown.is_empty() {
+		bail!("Unknown config keys in {}: {}", path.display(), unknown.join(", "));
+	}
+	Ok(())
+}
+
+/// The config in the XDG config dir: `wallpaper_carousel.nix`, or else the one of `wallpaper_carousel.<ext>` and
+/// `wallpaper_carousel/config.<ext>` that exists. Errors if several do, rather than picking one.
+fn xdg_config_file() -> Result<Option<PathBuf>> {
+	let Some(xdg_dir) = xdg::BaseDirectories::with_prefix("wallpaper_carousel")
+		.get_config_home()
+		.and_then(|dir| dir.parent().map(Path::to_path_buf))
+	else {
+		return Ok(None);
+	};
+	let nix = xdg_dir.join("wallpaper_carousel.nix");
+	if nix.exists() {
+		return Ok(Some(nix));
+	}
+	let found: Vec<PathBuf> = ["wallpaper_carousel", "wallpaper_carousel/config"]
+		.iter()
+		.flat_map(|base| ["toml", "json", "yaml", "json5", "ron", "ini"].map(|ext| xdg_dir.join(format!("{base}.{ext}"))))
+		.filter(|path| path.exists())
+		.collect();
+	if found.len() > 1 {
+		bail!("Multiple config files found: {found:?}");
+	}
+	Ok(found.into_iter().next())
+}
+
+/// Looked for in the working directory before the XDG location, for portable/project-local setups
+pub const PORTABLE_CONFIG_NAME: &str = "wallpaper_carousel.toml";
+
+impl AppConfig {
+	/// [`AppConfig::try_build`], but preferring `./wallpaper_carousel.toml` over the XDG config when no `--config` is given.
+	/// Unknown keys (typos, mostly) get a warning, or with `strict` fail the load.
+	pub fn try_build_portable(mut settings: SettingsFlags, strict: bool) -> Result<Self> {
+		let portable = PathBuf::from(PORTABLE_CONFIG_NAME);
+		let config_path = match &settings.config {
+			Some(explicit) => {
+				v_utils::elog!("Loading config from {}", explicit.0.display());
+				Some(explicit.0.clone())
+			}
+			None if portable.is_file() => {
+				let portable = std::env::current_dir().map(|cwd| cwd.join(&portable)).unwrap_or(portable);
+				v_utils::elog!("Loading portable config from {}", portable.display());
+				Some(portable)
+			}
+			None => {
+				let found = xdg_config_file()?;
+				if let Some(path) = &found {
+					v_utils::elog!("Loading config from {}", path.display());
+				}
+				found
+			}
+		};
+		// Handed to try_build explicitly, so the file it loads is the one includes resolve against and `strict` checks
+		settings.config = config_path.clone().map(v_utils::io::ExpandedPath);
+		let mut config = Self::try_build(settings)?;
+
+		// Includes are relative to the file doing the including, wherever that was found
+		let config_dir = match config_path.as_deref().and_then(|p| p.parent()) {
+			Some(dir) => dir.to_path_buf(),
+			None => xdg::BaseDirectories::with_prefix("wallpaper_carousel").get_config_home().unwrap_or_default(),
+		};
+		if strict && let Some(path) = &config_path {
+			deny_unknown_keys(path)?;
+		}
+		let mut include_stack = config_path.iter().filter_map(|p| p.canonicalize().ok()).collect();
+		config.merge_includes(&config_dir, &mut include_stack, strict)?;
+		config.expand_paths();
+
+		if let Some(path) = &config.quotes_json {
+			let raw = std::fs::read(path).wrap_err_with(|| format!("Failed to read quotes_json {}", path.display()))?;
+			let quotes: Vec<Quote> = serde_json::from_slice(&raw).wrap_err_with(|| format!("Failed to parse quotes_json {}", path.display()))?;
+			config.quotes.extend(quotes);
+		}
+
+		let dedupe = config.dedupe_quotes.unwrap_or(false);
+		// With a `quotes_url`, `active_quotes` checks them once the remote ones are in
+		if config.quotes_url.is_none() {
+			check_duplicate_quotes(&mut config.quotes, "quotes", dedupe);
+		}
+		for pool in config.pools.iter_mut().flatten() {
+			let name = pool.name.clone().unwrap_or_else(|| "unnamed pool".to_owned());
+			check_duplicate_quotes(&mut pool.quotes, &name, dedupe);
+		}
+		Ok(config)
+	}
+
+	/// Folds `include`d configs into this one, depth-first. `stack` holds the files currently being included, to catch cycles.
+	fn merge_includes(&mut self, dir: &Path, stack: &mut Vec<PathBuf>, strict: bool) -> Result<()> {
+		for include in self.include.take().unwrap_or_default() {
+			let path = dir.join(expand_path(&include));
+			let canonical = path.canonicalize().wrap_err_with(|| format!("Included config {} not found", path.display()))?;
+			if stack.contains(&canonical) {
+				bail!("Config include cycle: {} includes itself", canonical.display());
+			}
+			// try_build only ever sees the main file, so nothing else warns about these
+			match strict {
+				true => deny_unknown_keys(&canonical)?,
+				false =>
+					for key in unknown_keys(&canonical) {
+						v_utils::elog!("warning: unknown configuration field '{key}' in {} will be ignored", canonical.display());
+					},
+			}
+
+			let mut included: AppConfig = ::config::Config::builder()
+				.set_default("quotes", Vec::<::config::Value>::new())?
+				.add_source(::config::File::from(canonical.as_path()))
+				.build()
+				.and_then(::config::Config::try_deserialize)
+				.wrap_err_with(|| format!("Failed to load included config {}", canonical.display()))?;
+			// Relative paths in an included file are relative to it, not to wherever we were started from
+			let included_dir = canonical.parent().unwrap_or(Path::new("/"));
+			included.map_paths(|path| included_dir.join(expand_path(path)));
+			stack.push(canonical.clone());
+			included.merge_includes(included_dir, stack, strict)?;
+			stack.pop();
+
+			self.absorb(included);
+		}
+		Ok(())
+	}
+
+	/// `~` and `$VAR`s in every path-typed field
+	fn expand_paths(&mut self) {
+		self.map_paths(expand_path);
+	}
+
+	/// Rewrites every path-typed field with `f`.
+	fn map_paths(&mut self, f: impl Fn(&Path) -> PathBuf) {
+		for dir in self.font_dirs.iter_mut().flatten() {
+			*dir = f(dir);
+		}
+		for source in self.sources.iter_mut().flatten() {
+			source.dir = f(&source.dir);
+		}
+		for path in [&mut self.generate_background_dir, &mut self.lockscreen_image_path, &mut self.quotes_json].into_iter().flatten() {
+			*path = f(path);
+		}
+		if let Some(overlay_image) = &mut self.overlay_image {
+			overlay_image.path = f(&overlay_image.path);
+		}
+		for balance in self.balance.iter_mut().flat_map(|balances| balances.0.iter_mut()) {
+			if let Some(cwd) = &mut balance.cwd {
+				*cwd = f(cwd);
+			}
+		}
+	}
+
+	/// Lists are appended; everything else is only taken from `other` where this leaves it unset.
+	fn absorb(&mut self, other: AppConfig) {
+		self.quotes.extend(other.quotes);
+		if let Some(pools) = other.pools {
+			self.pools.get_or_insert_default().extend(pools);
+		}
+		if let Some(font_dirs) = other.font_dirs {
+			self.font_dirs.get_or_insert_default().extend(font_dirs);
+		}
+		if let Some(sources) = other.sources {
+			self.sources.get_or_insert_default().extend(sources);
+		}
+
+		macro_rules! fill_unset {
+			($($field:ident),* $(,)?) => {
+				$(if self.$field.is_none() {
+					self.$field = other.$field;
+				})*
+			};
+		}
+		fill_unset!(
+			balance,
+			text_padding,
+			selection_mode,
+			quote_max_lines,
+			circle_immediate,
+			line_spacing,
+			generate_background_dir,
+			dedupe_by_hash,
+			text_color,
+			scrim_color,
+			safe_area_min_width,
+			ignore_outputs,
+			text_opacity,
+			quote_opacity,
+			author_opacity,
+			balance_opacity,
+			circle_debounce_ms,
+			tab_width,
+			lockscreen_image_path,
+			output_format,
+			jpeg_quality,
+			webp_lossless,
+			webp_quality,
+			set_command,
+			brightness,
+			contrast,
+			quotes_json,
+			overlay_rotation,
+			lock_policy,
+			quotes_per_wallpaper,
+			output_dpi,
+			image_filter,
+			timestamp,
+			overlay_image,
+			dedupe_quotes,
+			max_overlay_fraction,
+			auto_scrim,
+			post_set_hook,
+			quotes_url,
+			caption_from_filename,
+			caption_pattern,
+			resize_filter,
+			quote_marks,
+			quote_mark_chars,
+			blacklist_hours,
+			text_antialiasing,
+			shape_antialiasing,
+			overlay_render_scale,
+			thirds_placement,
+		);
+	}
+
+	/// Quotes to sample from at the given local hour: the first pool whose window covers it, or the default `quotes`, with
+	/// those from `quotes_url` after them. Those are fetched here, once per run, rather than on load: it can wait on the
+	/// network, and most commands never pick a quote.
+	pub fn active_quotes(&self, hour: u32) -> &[Quote] {
+		static WITH_REMOTE: OnceLock<Vec<Quote>> = OnceLock::new();
+		match (self.active_pool(hour), &self.quotes_url) {
+			(Some(pool), _) => &pool.quotes,
+			(None, Some(url)) => WITH_REMOTE.get_or_init(|| {
+				let mut quotes = self.quotes.clone();
+				quotes.extend(remote_quotes(url));
+				check_duplicate_quotes(&mut quotes, "quotes", self.dedupe_quotes.unwrap_or(false));
+				quotes
+			}),
+			(None, None) => &self.quotes,
+		}
+	}
+
+	/// Opening and closing mark to put around the quote, if `quote_marks` is on. Falls back to `“”` if `quote_mark_chars`
+	/// isn't exactly two characters.
+	pub fn quote_marks(&self) -> Option<(char, char)> {
+		if !self.quote_marks.unwrap_or(false) {
+			return None;
+		}
+		let custom = self.quote_mark_chars.as_deref().and_then(|chars| match chars.chars().collect::<Vec<_>>()[..] {
+			[open, close] => Some((open, close)),
+			_ => {
+				v_utils::elog!("Warning: quote_mark_chars should be exactly two characters (opening, closing), got {chars:?}");
+				None
+			}
+		});
+		Some(custom.unwrap_or(('“', '”')))
+	}
+
+	/// The first pool whose window covers the given local hour, if any.
+	pub fn active_pool(&self, hour: u32) -> Option<&QuotePool> {
+		self.pools.iter().flatten().find(|pool| pool.hours.contains(hour))
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct BackgroundSource {
+	pub dir: PathBuf,
+	/// Relative to the other sources' weights; 1 if unset
+	pub weight: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct QuotePool {
+	pub name: Option<String>,
+	pub hours: HourWindow,
+	pub quotes: Vec<Quote>,
+}
+
+/// `"06-12"`: from 06:00 up to (not including) 12:00. Windows like `"22-06"` wrap over midnight.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HourWindow {
+	pub start: u32,
+	pub end: u32,
+}
+
+impl HourWindow {
+	pub fn contains(&self, hour: u32) -> bool {
+		if self.start <= self.end {
+			self.start <= hour && hour < self.end
+		} else {
+			hour >= self.start || hour < self.end
+		}
+	}
+}
+
+impl std::str::FromStr for HourWindow {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (start, end) = s.split_once('-').ok_or_else(|| color_eyre::eyre::eyre!("Expected hours as \"HH-HH\", got {s:?}"))?;
+		let start: u32 = start.trim().parse().wrap_err_with(|| format!("Invalid start hour in {s:?}"))?;
+		let end: u32 = end.trim().parse().wrap_err_with(|| format!("Invalid end hour in {s:?}"))?;
+		if start > 24 || end > 24 {
+			bail!("Hours must be within 0-24, got {s:?}");
+		}
+		Ok(Self { start, end })
+	}
+}
+
+impl JsonSchema for HourWindow {
+	fn schema_name() -> Cow<'static, str> {
+		"HourWindow".into()
+	}
+
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		json_schema!({
+			"description": "\"06-12\": from 06:00 up to (not including) 12:00. Windows like \"22-06\" wrap over midnight.",
+			"type": "string",
+			"pattern": "^\\s*\\d{1,2}\\s*-\\s*\\d{1,2}\\s*$",
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for HourWindow {
+	fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+	where
+		D: Deserializer<'de>, {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(serde::de::Error::custom)
+	}
+}
+
+/// Either plain pixels (`15`), or a percentage of the safe area (`"2%"`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Padding {
+	Pixels(u32),
+	Percent(f32),
+}
+
+impl Padding {
+	/// Resolves to pixels; percentages are taken of the safe area's smaller side, so padding is the same both ways.
+	pub fn resolve(&self, area_width: u32, area_height: u32) -> u32 {
+		match *self {
+			Padding::Pixels(px) => px,
+			Padding::Percent(pct) => (area_width.min(area_height) as f32 * pct / 100.0).round() as u32,
+		}
+	}
+}
+
+impl std::str::FromStr for Padding {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let s = s.trim();
+		match s.strip_suffix('%') {
+			Some(pct) => Ok(Padding::Percent(pct.trim().parse().wrap_err_with(|| format!("Invalid padding percentage: {s:?}"))?)),
+			None => Ok(Padding::Pixels(s.strip_suffix("px").unwrap_or(s).parse().wrap_err_with(|| format!("Invalid padding: {s:?}"))?)),
+		}
+	}
+}
+
+impl JsonSchema for Padding {
+	fn schema_name() -> Cow<'static, str> {
+		"Padding".into()
+	}
+
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		json_schema!({
+			"description": "Either plain pixels (15), or a percentage of the safe area (\"2%\").",
+			"anyOf": [
+				{ "type": "integer", "minimum": 0 },
+				{ "type": "string", "pattern": "^\\s*\\d+(\\.\\d+)?\\s*(%|px)?\\s*$" },
+			],
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for Padding {
+	fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+	where
+		D: Deserializer<'de>, {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum PaddingHelper {
+			Pixels(u32),
+			String(String),
+		}
+
+		match PaddingHelper::deserialize(deserializer)? {
+			PaddingHelper::Pixels(px) => Ok(Padding::Pixels(px)),
+			PaddingHelper::String(s) => s.parse().map_err(serde::de::Error::custom),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverlayRotation {
+	/// Whatever the first rotated active output's `transform` is
+	Auto,
+	/// Always one of 0, 90, 180, 270
+	Degrees(u16),
+	#[default]
+	None,
+}
+
+impl std::str::FromStr for OverlayRotation {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim() {
+			"auto" => Ok(OverlayRotation::Auto),
+			degrees => match degrees.parse::<u16>() {
+				Ok(0) => Ok(OverlayRotation::None),
+				Ok(degrees @ (90 | 180 | 270)) => Ok(OverlayRotation::Degrees(degrees)),
+				_ => bail!("Overlay rotation must be 0, 90, 180, 270 or \"auto\", got {s:?}"),
+			},
+		}
+	}
+}
+
+impl JsonSchema for OverlayRotation {
+	fn schema_name() -> Cow<'static, str> {
+		"OverlayRotation".into()
+	}
+
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		json_schema!({
+			"description": "Clockwise degrees, or \"auto\" for the first rotated active output's transform",
+			"anyOf": [
+				{ "enum": [0, 90, 180, 270] },
+				{ "enum": ["0", "90", "180", "270", "auto"] },
+			],
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for OverlayRotation {
+	fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+	where
+		D: Deserializer<'de>, {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum RotationHelper {
+			Degrees(u16),
+			String(String),
+		}
+
+		match RotationHelper::deserialize(deserializer)? {
+			RotationHelper::Degrees(degrees) => degrees.to_string().parse().map_err(serde::de::Error::custom),
+			RotationHelper::String(s) => s.parse().map_err(serde::de::Error::custom),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockPolicy {
+	/// SIGTERM the previous holder and take over; its result is outdated anyway
+	#[default]
+	Kill,
+	/// Let the previous holder finish first
+	Wait,
+	/// Exit with an error, leaving the previous holder be
+	Abort,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct OverlayImage {
+	pub path: PathBuf,
+	/// Corner of the safe area; bottom-right by default, as the text takes the top-right
+	pub position: Option<Corner>,
+	/// Of the image's own size
+	pub scale: Option<f32>,
+	/// Draw over the text rather than under it
+	#[serde(default)]
+	pub above_text: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+	TopLeft,
+	TopRight,
+	BottomLeft,
+	#[default]
+	BottomRight,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFilter {
+	Grayscale,
+	Sepia,
+	Invert,
+	/// Gaussian, for a backdrop the text stands out from
+	Blur,
+}
+
+impl std::str::FromStr for ImageFilter {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim() {
+			"grayscale" => Ok(ImageFilter::Grayscale),
+			"sepia" => Ok(ImageFilter::Sepia),
+			"invert" => Ok(ImageFilter::Invert),
+			"blur" => Ok(ImageFilter::Blur),
+			_ => bail!("Unknown image filter {s:?}, expected one of: grayscale, sepia, invert, blur"),
+		}
+	}
+}
+
+/// Mirrors `image::imageops::FilterType`, fastest first
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeFilter {
+	Nearest,
+	Triangle,
+	CatmullRom,
+	Gaussian,
+	#[default]
+	Lanczos3,
+}
+
+impl ResizeFilter {
+	pub fn filter_type(self) -> image::imageops::FilterType {
+		use image::imageops::FilterType;
+
+		match self {
+			ResizeFilter::Nearest => FilterType::Nearest,
+			ResizeFilter::Triangle => FilterType::Triangle,
+			ResizeFilter::CatmullRom => FilterType::CatmullRom,
+			ResizeFilter::Gaussian => FilterType::Gaussian,
+			ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+		}
+	}
+}
+
+impl std::str::FromStr for ResizeFilter {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim() {
+			"nearest" => Ok(ResizeFilter::Nearest),
+			"triangle" => Ok(ResizeFilter::Triangle),
+			"catmull_rom" => Ok(ResizeFilter::CatmullRom),
+			"gaussian" => Ok(ResizeFilter::Gaussian),
+			"lanczos3" => Ok(ResizeFilter::Lanczos3),
+			_ => bail!("Unknown resize filter {s:?}, expected one of: nearest, triangle, catmull_rom, gaussian, lanczos3"),
+		}
+	}
+}
+
+/// Encoding of the generated wallpaper
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+	#[default]
+	Png,
+	#[serde(alias = "jpg")]
+	Jpeg,
+	Webp,
+}
+
+impl OutputFormat {
+	pub fn extension(&self) -> &'static str {
+		match self {
+			OutputFormat::Png => "png",
+			OutputFormat::Jpeg => "jpg",
+			OutputFormat::Webp => "webp",
+		}
+	}
+}
+
+/// How a quote is picked out of `quotes` on each generation.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+	/// Fresh random pick every run
+	#[default]
+	Random,
+	/// Same quote for the whole (local) day, changes at midnight
+	Daily,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Balance {
 	pub command: String,
 	pub label: Option<String>,
+	/// Position among the balance blocks, lowest first. Unordered ones go last, in config order.
+	pub order: Option<i32>,
+	pub font_size: Option<u32>,
+	/// Any SVG/CSS color
+	pub color: Option<String>,
+	/// Directory the command runs in; `~` and `$VAR`s are expanded. The tool's own working directory if unset.
+	pub cwd: Option<PathBuf>,
+	/// Set on the command, over whatever it inherits
+	#[serde(default)]
+	pub env: HashMap<String, String>,
+	/// Start the command from an empty environment, so it only sees `env`
+	#[serde(default)]
+	pub clear_env: bool,
+	/// Put before the number, e.g. `"$"`. Setting any of `prefix`/`suffix`/`decimals`/`thousands_separator` means the output
+	/// is expected to be a number; if it isn't, it's shown as is.
+	pub prefix: Option<String>,
+	pub suffix: Option<String>,
+	/// Round to this many digits after the point
+	pub decimals: Option<usize>,
+	/// Put between every three digits of the integer part, e.g. `","`
+	pub thousands_separator: Option<String>,
+}
+
+/// Accepts both a single `balance` table and a `[[balance]]` array of them.
+#[derive(Clone, Debug, Default)]
+pub struct Balances(pub Vec<Balance>);
+
+impl Balances {
+	/// Balances sorted by their `order`; stable, so ties keep config order.
+	pub fn ordered(&self) -> Vec<&Balance> {
+		let mut balances: Vec<&Balance> = self.0.iter().collect();
+		balances.sort_by_key(|b| (b.order.is_none(), b.order));
+		balances
+	}
+}
+
+impl JsonSchema for Balances {
+	fn schema_name() -> Cow<'static, str> {
+		"Balances".into()
+	}
+
+	fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+		let one = generator.subschema_for::<Balance>();
+		let many = generator.subschema_for::<Vec<Balance>>();
+		json_schema!({
+			"description": "A single `balance` table, or a `[[balance]]` array of them",
+			"anyOf": [one, many],
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for Balances {
+	fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+	where
+		D: Deserializer<'de>, {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum BalancesHelper {
+			One(Box<Balance>),
+			Many(Vec<Balance>),
+		}
+
+		Ok(match BalancesHelper::deserialize(deserializer)? {
+			BalancesHelper::One(balance) => Balances(vec![*balance]),
+			BalancesHelper::Many(balances) => Balances(balances),
+		})
+	}
 }
 
 impl Balance {
 	pub fn get_value(&self) -> Result<String> {
-		let output = Command::new("sh").arg("-c").arg(&self.command).output().wrap_err("Failed to execute balance command")?;
+		let mut command = Command::new("sh");
+		command.arg("-c").arg(&self.command);
+		if let Some(cwd) = &self.cwd {
+			command.current_dir(cwd);
+		}
+		if self.clear_env {
+			command.env_clear();
+		}
+		command.envs(&self.env);
+		let output = command.output().wrap_err("Failed to execute balance command")?;
 
 		if !output.status.success() {
 			let stderr = String::from_utf8_lossy(&output.stderr);
@@ -45,6 +1015,38 @@ impl Balance {
 pub struct Quote {
 	pub text: String,
 	pub author: Option<String>,
+	/// Book, URL, etc. the quote is from
+	pub source: Option<String>,
+	/// Overrides `text_color` for this quote's text (not its author/source, nor the balance)
+	pub color: Option<String>,
+	pub tags: Vec<String>,
+}
+
+impl JsonSchema for Quote {
+	fn schema_name() -> Cow<'static, str> {
+		"Quote".into()
+	}
+
+	fn json_schema(_: &mut SchemaGenerator) -> Schema {
+		json_schema!({
+			"description": "Just the text, or a table with the text and what goes along with it",
+			"anyOf": [
+				{ "type": "string" },
+				{
+					"type": "object",
+					"properties": {
+						"text": { "type": "string" },
+						"author": { "type": "string" },
+						"source": { "type": "string", "description": "Book, URL, etc. the quote is from" },
+						"color": { "type": "string", "description": "Overrides `text_color` for this quote's text (not its author/source, nor the balance)" },
+						"tags": { "type": "array", "items": { "type": "string" } },
+					},
+					"required": ["text"],
+					"additionalProperties": false,
+				},
+			],
+		})
+	}
 }
 
 impl<'de> Deserialize<'de> for Quote {
@@ -55,13 +1057,68 @@ impl<'de> Deserialize<'de> for Quote {
 		#[serde(untagged)]
 		enum QuoteHelper {
 			String(String),
-			Structured { text: String, author: Option<String> },
+			Structured {
+				text: String,
+				author: Option<String>,
+				source: Option<String>,
+				color: Option<String>,
+				#[serde(default)]
+				tags: Vec<String>,
+			},
 		}
 
 		let helper = QuoteHelper::deserialize(deserializer)?;
 		Ok(match helper {
-			QuoteHelper::String(text) => Quote { text, author: None },
-			QuoteHelper::Structured { text, author } => Quote { text, author },
+			QuoteHelper::String(text) => Quote {
+				text,
+				author: None,
+				source: None,
+				color: None,
+				tags: Vec::new(),
+			},
+			QuoteHelper::Structured { text, author, source, color, tags } => Quote { text, author, source, color, tags },
+		})
+	}
+}
+
+/// `image.jpg.toml` next to a wallpaper, carrying its own caption in place of one sampled from the config.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Sidecar {
+	pub quote: Option<String>,
+	pub author: Option<String>,
+	/// Photographer, license, etc.; shown where a quote's `source` would be
+	pub credit: Option<String>,
+}
+
+impl Sidecar {
+	pub fn path_for(image: &Path) -> PathBuf {
+		let mut path = image.as_os_str().to_owned();
+		path.push(".toml");
+		PathBuf::from(path)
+	}
+
+	/// `None` when the image has no sidecar.
+	pub fn load(image: &Path) -> Result<Option<Self>> {
+		let path = Self::path_for(image);
+		if !path.is_file() {
+			return Ok(None);
+		}
+		let sidecar = ::config::Config::builder()
+			.add_source(::config::File::from(path.as_path()))
+			.build()
+			.and_then(::config::Config::try_deserialize)
+			.wrap_err_with(|| format!("Failed to load sidecar {}", path.display()))?;
+		Ok(Some(sidecar))
+	}
+
+	/// The sidecar's own quote, if it has one.
+	pub fn quote(&self) -> Option<Quote> {
+		Some(Quote {
+			text: self.quote.clone()?,
+			author: self.author.clone(),
+			source: self.credit.clone(),
+			color: None,
+			tags: Vec::new(),
 		})
 	}
 }
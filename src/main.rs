@@ -1,29 +1,262 @@
 use std::{
+	collections::{HashMap, HashSet},
+	ffi::OsString,
+	hash::{DefaultHasher, Hash, Hasher},
+	io::Write,
 	path::{Path, PathBuf},
 	process::Command as ProcessCommand,
 	sync::Arc,
 };
 
-use clap::Parser;
+use clap::{CommandFactory as _, Parser, ValueEnum as _};
 use color_eyre::{
 	Result,
-	eyre::{Context, ContextCompat, bail},
+	eyre::{Context, ContextCompat, bail, eyre},
 };
 use image::GenericImageView;
-use rand::prelude::IndexedRandom;
-use serde::Deserialize;
+use rand::{SeedableRng, prelude::IndexedRandom, rngs::StdRng};
+use serde::{Deserialize, Serialize};
 use tracing::{info, warn};
 use v_utils::utils::eyre::exit_on_error;
-use wallpaper_carousel::config::{AppConfig, SettingsFlags};
+use wallpaper_carousel::config::{
+	AppConfig, BackgroundSource, Balance, Corner, ImageFilter, LockPolicy, OutputFormat, OverlayImage, OverlayRotation, Padding, Quote, SelectionMode, SettingsFlags, Sidecar,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "wallpaper_carousel")]
 #[command(about = "Extend wallpaper with citation overlays")]
 struct Args {
 	#[command(subcommand)]
-	command: Command,
+	command: Option<Command>,
 	#[command(flatten)]
 	settings: SettingsFlags,
+	/// Additional directory to load fonts from (repeatable, added to `font_dirs` from config)
+	#[arg(long)]
+	font_dir: Vec<PathBuf>,
+	/// Fail on unknown config keys instead of warning about them
+	#[arg(long)]
+	strict_config: bool,
+	/// Load and validate the config, syntax-checking balance commands without running them, then exit; nothing is set or written
+	#[arg(long)]
+	config_check: bool,
+}
+
+/// Per-run tweaks to how a wallpaper is generated
+#[derive(Clone, Debug, Default, clap::Args)]
+struct GenerateOptions {
+	/// For animated inputs (gif, webp), use this frame (0-based) instead of the first one
+	#[arg(long)]
+	frame: Option<usize>,
+
+	/// Show this as the balance instead of running the balance command (keeps the configured label)
+	#[arg(long, alias = "balance-override")]
+	balance_text: Option<String>,
+
+	/// Leave the balance out this time, without running its commands (e.g. when sharing the screen)
+	#[arg(long, conflicts_with = "balance_text")]
+	skip_balance: bool,
+
+	/// Default text and scrim colors; `text_color`/`scrim_color` from config still take precedence
+	#[arg(long)]
+	theme: Option<Theme>,
+
+	/// Render only the author line, without the quote text
+	#[arg(long, conflicts_with = "quote_only")]
+	author_only: bool,
+
+	/// Render only the quote text, without the author line
+	#[arg(long)]
+	quote_only: bool,
+
+	/// Read the output list from this JSON file (same shape as `swaymsg -t get_outputs`) instead of asking sway
+	#[arg(long, hide = true)]
+	outputs_from: Option<PathBuf>,
+
+	/// Also copy the result to `lockscreen_image_path` (e.g. for `swaylock --image`)
+	#[arg(long)]
+	also_lockscreen: bool,
+
+	/// Print a report of the layout decisions: crop windows, safe area, text positions, chosen quote
+	#[arg(long)]
+	explain: bool,
+
+	/// Only pick quotes carrying this tag; repeatable, in which case a quote needs all of them
+	#[arg(long)]
+	tag: Vec<String>,
+
+	/// With several `--tag`s, any one of them is enough
+	#[arg(long, requires = "tag")]
+	any_tag: bool,
+
+	/// Log how many quotes (and sources) were left after each selection stage, and the odds of the final pick
+	#[arg(long)]
+	explain_selection: bool,
+
+	/// Log how long each stage of generation took
+	#[arg(long)]
+	verbose_timing: bool,
+
+	/// Write only the text overlay, as a transparent PNG at display resolution, to this path; nothing gets set
+	#[arg(long)]
+	overlay_only: Option<PathBuf>,
+
+	/// Do nothing if image, quotes, balance and displays are all the same as for the wallpaper currently up. Only ever the case
+	/// when that image comes up again, e.g. a daemon rotating through a directory of one
+	#[arg(long)]
+	skip_unchanged: bool,
+
+	/// Where the image goes with `--stdout`; the process' own stdout is pointed at stderr, so logs can't corrupt it
+	#[arg(skip)]
+	stdout_sink: Option<Arc<std::fs::File>>,
+
+	/// Set by `refresh`: the current image is only re-rendered, so it doesn't go into the history again
+	#[arg(skip)]
+	rerender: bool,
+
+	/// Set by `generate --solid/--gradient`: the background is synthesized at display resolution instead of decoded
+	#[arg(skip)]
+	plain_background: Option<PlainBackground>,
+
+	/// Set by `generate --out-dir`: save the result here (extension added) and set nothing
+	#[arg(skip)]
+	output_to: Option<PathBuf>,
+
+	/// Set by `--output`: the one sway output that is laid out for and set, instead of all of them
+	#[arg(skip)]
+	target_output: Option<String>,
+}
+
+impl GenerateOptions {
+	/// The flags these were parsed from, for handing on to a child run of ourselves. The `#[arg(skip)]` ones are set by the
+	/// subcommand, so the child's own subcommand takes care of those.
+	fn to_args(&self) -> Vec<OsString> {
+		let switches = [
+			("--skip-balance", self.skip_balance),
+			("--author-only", self.author_only),
+			("--quote-only", self.quote_only),
+			("--also-lockscreen", self.also_lockscreen),
+			("--explain", self.explain),
+			("--any-tag", self.any_tag),
+			("--explain-selection", self.explain_selection),
+			("--verbose-timing", self.verbose_timing),
+			("--skip-unchanged", self.skip_unchanged),
+		];
+		let values: [(&str, Option<OsString>); 5] = [
+			("--frame", self.frame.map(|frame| frame.to_string().into())),
+			("--balance-text", self.balance_text.clone().map(Into::into)),
+			("--theme", self.theme.and_then(|theme| theme.to_possible_value()).map(|value| value.get_name().into())),
+			("--outputs-from", self.outputs_from.clone().map(Into::into)),
+			("--overlay-only", self.overlay_only.clone().map(Into::into)),
+		];
+		let mut args: Vec<OsString> = switches.into_iter().filter(|&(_, on)| on).map(|(flag, _)| flag.into()).collect();
+		for (flag, value) in values {
+			if let Some(value) = value {
+				args.extend([flag.into(), value]);
+			}
+		}
+		for tag in &self.tag {
+			args.extend(["--tag".into(), tag.into()]);
+		}
+		args
+	}
+}
+
+/// The flags before the subcommand (config path and overrides, font dirs, ...), as we were started with them; a child run
+/// of ourselves gets them too, so it loads the same config.
+fn top_level_args() -> Vec<OsString> {
+	let command = Args::command();
+	let takes_value = |flag: &str| {
+		let matches = |arg: &&clap::Arg| match flag.strip_prefix("--") {
+			Some(long) => arg.get_long() == Some(long),
+			None => flag
+				.strip_prefix('-')
+				.is_some_and(|short| short.len() == 1 && arg.get_short().is_some_and(|c| short.starts_with(c))),
+		};
+		command.get_arguments().find(matches).is_some_and(|arg| arg.get_action().takes_values())
+	};
+	let mut argv = std::env::args_os().skip(1);
+	let mut args = Vec::new();
+	// The first word that isn't a flag or a flag's value is the subcommand
+	while let Some(arg) = argv.next() {
+		let flag = arg.to_string_lossy().into_owned();
+		if !flag.starts_with('-') {
+			break;
+		}
+		args.push(arg);
+		if !flag.contains('=') && takes_value(&flag) {
+			args.extend(argv.next());
+		}
+	}
+	args
+}
+
+/// A background made up rather than loaded
+#[derive(Clone, Copy, Debug)]
+enum PlainBackground {
+	Solid([u8; 3]),
+	/// Top to bottom
+	Gradient([u8; 3], [u8; 3]),
+}
+
+impl PlainBackground {
+	fn render(&self, width: u32, height: u32) -> image::DynamicImage {
+		let (top, bottom) = match *self {
+			PlainBackground::Solid(color) => (color, color),
+			PlainBackground::Gradient(top, bottom) => (top, bottom),
+		};
+		image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |_, y| {
+			let t = y as f32 / height.saturating_sub(1).max(1) as f32;
+			image::Rgb(std::array::from_fn(|i| (top[i] as f32 + (bottom[i] as f32 - top[i] as f32) * t).round() as u8))
+		}))
+	}
+}
+
+/// `#rrggbb`, the `#` being optional
+fn parse_hex_color(s: &str) -> std::result::Result<[u8; 3], String> {
+	let hex = s.trim().trim_start_matches('#');
+	if hex.len() != 6 || !hex.is_ascii() {
+		return Err(format!("Expected a color as #rrggbb, got {s:?}"));
+	}
+	let channel = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| format!("Invalid hex color {s:?}"));
+	Ok([channel(0)?, channel(1)?, channel(2)?])
+}
+
+fn parse_gradient(s: &str) -> std::result::Result<([u8; 3], [u8; 3]), String> {
+	let (top, bottom) = s.split_once(',').ok_or_else(|| format!("Expected two colors as #rrggbb,#rrggbb, got {s:?}"))?;
+	Ok((parse_hex_color(top)?, parse_hex_color(bottom)?))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+enum CircleMode {
+	/// One image, stretched over every output
+	#[default]
+	Same,
+	/// A separate random image on each active output. The text overlay is skipped, as there's no single image to put it on.
+	RandomPerOutput,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Theme {
+	/// White text over a dark scrim
+	Dark,
+	/// Dark text over a light scrim
+	Light,
+}
+
+impl Theme {
+	fn text_color(self) -> &'static str {
+		match self {
+			Theme::Dark => "white",
+			Theme::Light => "#111111",
+		}
+	}
+
+	fn scrim_color(self) -> &'static str {
+		match self {
+			Theme::Dark => "rgba(0, 0, 0, 0.4)",
+			Theme::Light => "rgba(255, 255, 255, 0.5)",
+		}
+	}
 }
 #[derive(Debug, Parser)]
 enum Command {
@@ -31,10 +264,83 @@ enum Command {
 	Extend {
 		/// Path to input image file (jpg or png). If not provided, uses the last input file from cache.
 		input: Option<PathBuf>,
+
+		/// Only change this sway output's wallpaper (e.g. "HDMI-A-1"), laying the text out for it alone
+		#[arg(long)]
+		output: Option<String>,
+
+		/// Write the PNG to stdout instead of setting it as wallpaper (for previewing, e.g. `| kitten icat`)
+		#[arg(long)]
+		stdout: bool,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
+
+	/// Set this image as the wallpaper, with the overlay, and remember it as the current one (what `extend` does with a path)
+	Apply {
+		image: PathBuf,
+
+		/// Only change this sway output's wallpaper (e.g. "HDMI-A-1"), laying the text out for it alone
+		#[arg(long)]
+		output: Option<String>,
+
+		/// Write the PNG to stdout instead of setting it as wallpaper (for previewing, e.g. `| kitten icat`)
+		#[arg(long)]
+		stdout: bool,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
 	},
 
 	/// Generate wallpaper using the bundled vision document
-	Generate,
+	Generate {
+		/// Use the last built vision image as is, even if its sources are newer (doesn't need typst)
+		#[arg(long, conflicts_with = "force_regen")]
+		no_regen: bool,
+
+		/// Recompile the vision document even if its sources look unchanged (e.g. after editing an asset outside src_typ)
+		#[arg(long)]
+		force_regen: bool,
+
+		/// Put the text on a plain color (#rrggbb) instead of an image; the whole screen is the safe area
+		#[arg(long, value_parser = parse_hex_color, conflicts_with_all = ["gradient", "no_regen", "force_regen"])]
+		solid: Option<[u8; 3]>,
+
+		/// Like --solid, but a vertical gradient between two colors: #rrggbb,#rrggbb
+		#[arg(long, value_parser = parse_gradient, conflicts_with_all = ["no_regen", "force_regen"])]
+		gradient: Option<([u8; 3], [u8; 3])>,
+
+		/// Write wallpapers into this directory instead of setting one (e.g. to prepare a screensaver folder)
+		#[arg(long)]
+		out_dir: Option<PathBuf>,
+
+		/// How many to write into --out-dir, each with its own random quote (and photo, with `generate_background_dir`)
+		#[arg(long, requires = "out_dir")]
+		count: Option<usize>,
+
+		/// Only change this sway output's wallpaper (e.g. "HDMI-A-1"), laying the text out for it alone
+		#[arg(long)]
+		output: Option<String>,
+
+		/// Write the PNG to stdout instead of setting it as wallpaper (for previewing, e.g. `| kitten icat`)
+		#[arg(long)]
+		stdout: bool,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
+
+	/// Re-render the current wallpaper (e.g. after a config change) without advancing to another image
+	#[command(alias = "set-current")]
+	Refresh {
+		/// Write the PNG to stdout instead of setting it as wallpaper (for previewing, e.g. `| kitten icat`)
+		#[arg(long)]
+		stdout: bool,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
 
 	/// Circle through images in the same directory
 	Circle {
@@ -46,14 +352,136 @@ enum Command {
 		#[arg(short, long, conflicts_with_all = ["forward", "random"])]
 		backwards: bool,
 
-		/// Select a random image
+		/// Select a random image; from the weighted `sources` if configured and no directory is given
 		#[arg(short, long, conflicts_with_all = ["forward", "backwards"])]
 		random: bool,
 
+		/// If the directory holds just one image, re-apply it instead of failing
+		#[arg(long)]
+		allow_single: bool,
+
+		/// Walk a persisted random permutation of the directory instead of the sorted order
+		#[arg(short, long, conflicts_with = "random")]
+		shuffle: bool,
+
+		/// How images are spread over the outputs
+		#[arg(long, value_enum, default_value_t, conflicts_with_all = ["forward", "backwards", "shuffle"])]
+		mode: CircleMode,
+
+		/// Step through this list of images (one path per line, relative to the file) instead of a directory
+		#[arg(long, conflicts_with_all = ["directory", "shuffle", "mode"])]
+		playlist: Option<PathBuf>,
+
+		/// Only change this sway output's wallpaper (e.g. "HDMI-A-1"), laying the text out for it alone
+		#[arg(long, conflicts_with = "mode")]
+		output: Option<String>,
+
+		/// Keep the current image out of rotation for `blacklist_hours`, and move on from it (forwards, unless told otherwise)
+		#[arg(long, conflicts_with = "playlist")]
+		blacklist: bool,
+
+		/// Let every blacklisted image back into rotation
+		#[arg(long, exclusive = true)]
+		clear_blacklist: bool,
+
+		/// Optional directory to use instead of the parent of last input
+		directory: Option<PathBuf>,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
+
+	/// Step through a directory in the terminal (n/p, enter to apply, q to quit)
+	Browse {
+		directory: PathBuf,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
+
+	/// Re-set the current wallpaper darkened as a whole (e.g. to focus); `dim 0` restores it
+	Dim {
+		/// 0.0 (unchanged) to 1.0 (black)
+		#[arg(value_parser = parse_dim)]
+		amount: f32,
+	},
+
+	/// Summarize which images and quotes have been shown, and when last
+	Stats,
+
+	/// Check the environment: sway (or xrandr and feh on X11), typst, fonts, config and the vision bundle
+	Doctor,
+
+	/// Print a JSON Schema of the config file, for editors to autocomplete and check it against
+	Schema,
+
+	/// Remove every cache and state file the tool keeps (history, positions, blacklist, temp backgrounds, ...), listing each.
+	/// Images, and the generated wallpaper currently up, are left alone.
+	Clean,
+
+	/// Time resize + text compositing on a synthetic image; doesn't touch sway, balance commands or the cache
+	Benchmark {
+		/// Output size, as WIDTHxHEIGHT
+		#[arg(long, default_value = "3840x2160", value_parser = parse_resolution)]
+		resolution: (u32, u32),
+
+		#[arg(long, default_value_t = 20)]
+		iterations: usize,
+	},
+
+	/// Render the safe area and text bounding boxes over an image, for tuning the layout
+	DebugSafeArea {
+		/// Image to lay the overlay out on
+		input: PathBuf,
+
+		/// Where to write the debug image
+		#[arg(short, long, default_value = "safe_area_debug.png")]
+		output: PathBuf,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
+	},
+
+	/// Keep rotating through images in the directory of the last input, one overlay per rotation
+	///
+	/// Each rotation runs as a one-shot `extend` child process, so the daemon itself never holds the generation lock.
+	Daemon {
+		/// Do a single rotation and exit (for systemd `Type=oneshot` timers)
+		#[arg(long)]
+		once: bool,
+
+		/// Seconds between rotations
+		#[arg(short, long, default_value_t = 1800)]
+		interval: u64,
+
+		/// Pick a random image instead of the next one
+		#[arg(short, long)]
+		random: bool,
+
 		/// Optional directory to use instead of the parent of last input
 		directory: Option<PathBuf>,
+
+		#[command(flatten)]
+		generate: GenerateOptions,
 	},
 }
+
+impl Command {
+	/// The generation flags, for the subcommands that take them
+	fn generate_options(&mut self) -> Option<&mut GenerateOptions> {
+		match self {
+			Command::Extend { generate, .. }
+			| Command::Apply { generate, .. }
+			| Command::Generate { generate, .. }
+			| Command::Refresh { generate, .. }
+			| Command::Circle { generate, .. }
+			| Command::Browse { generate, .. }
+			| Command::DebugSafeArea { generate, .. }
+			| Command::Daemon { generate, .. } => Some(generate),
+			Command::Dim { .. } | Command::Stats | Command::Doctor | Command::Schema | Command::Clean | Command::Benchmark { .. } => None,
+		}
+	}
+}
 fn main() {
 	v_utils::clientside!();
 	exit_on_error(run());
@@ -61,8 +489,39 @@ fn main() {
 
 #[derive(Debug, Deserialize)]
 struct SwayOutput {
+	/// e.g. "DP-1"
+	name: String,
 	/// None for inactive outputs (e.g., unplugged HDMI)
 	current_mode: Option<CurrentMode>,
+	/// Layout-space (logical) geometry, after scale and transform
+	rect: Option<OutputRect>,
+	scale: Option<f64>,
+	/// "normal", "90", "180", "270", or "flipped-*" versions of those
+	transform: Option<String>,
+}
+
+impl SwayOutput {
+	/// Pixel dimensions of what's actually visible on this output, `None` if it's inactive.
+	fn effective_resolution(&self) -> Option<(u32, u32)> {
+		let mode = self.current_mode.as_ref()?;
+		// rect * scale is the visible region in physical pixels, rotation and fractional scaling included
+		if let (Some(rect), Some(scale)) = (&self.rect, self.scale)
+			&& rect.width > 0
+			&& rect.height > 0
+			&& scale > 0.0
+		{
+			return Some(((rect.width as f64 * scale).round() as u32, (rect.height as f64 * scale).round() as u32));
+		}
+		// Older sway/fixture without rect: at least account for rotation
+		let rotated = self.transform.as_deref().is_some_and(|t| t.ends_with("90") || t.ends_with("270"));
+		Some(if rotated { (mode.height, mode.width) } else { (mode.width, mode.height) })
+	}
+
+	/// Clockwise degrees of the `transform`, ignoring flips
+	fn rotation(&self) -> u16 {
+		let transform = self.transform.as_deref().unwrap_or("normal");
+		transform.strip_prefix("flipped-").unwrap_or(transform).parse().unwrap_or(0)
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -71,6 +530,12 @@ struct CurrentMode {
 	height: u32,
 }
 
+#[derive(Debug, Deserialize)]
+struct OutputRect {
+	width: u32,
+	height: u32,
+}
+
 #[derive(Clone, Debug)]
 struct SafeArea {
 	x: u32,
@@ -79,16 +544,143 @@ struct SafeArea {
 	height: u32,
 }
 
+/// A rendered balance, ready to be laid out
+struct BalanceBlock {
+	text: String,
+	font_size: u32,
+	color: Option<String>,
+}
+
 struct CompositeParams<'a> {
 	bg_image_path: &'a Path,
-	output_path: &'a Path,
-	text: &'a str,
+	text: Option<&'a str>,
 	author: Option<&'a str>,
-	balance: Option<&'a str>,
+	source: Option<&'a str>,
+	balances: &'a [BalanceBlock],
 	width: u32,
 	height: u32,
 	safe_area: &'a SafeArea,
 	text_padding: u32,
+	quote_max_lines: Option<usize>,
+	/// In em
+	line_spacing: f32,
+	/// Spaces each tab expands to
+	tab_width: usize,
+	font_dirs: &'a [PathBuf],
+	/// Any SVG/CSS color
+	text_color: &'a str,
+	/// Overrides `text_color` for the quote text alone
+	quote_color: Option<&'a str>,
+	/// Backdrop behind the whole text block, none if unset
+	scrim_color: Option<&'a str>,
+	opacity: TextOpacity,
+	/// Clockwise degrees (0, 90, 180, 270) the overlay is turned by; the text is laid out upright in the turned frame
+	rotation: u16,
+	/// Further quotes, each taking the next of `EXTRA_QUOTE_CORNERS`
+	extra_quotes: &'a [QuoteBlock<'a>],
+	/// Generation time, already formatted; a small line under the balances
+	timestamp: Option<&'a str>,
+	/// From the image's file name; a line under the timestamp
+	caption: Option<&'a str>,
+	overlay_image: Option<&'a OverlayImage>,
+	/// Cap on the main block's share of the safe area's height; fonts shrink to respect it
+	max_overlay_fraction: Option<f32>,
+	/// Opening and closing mark around the main quote's text
+	quote_marks: Option<(char, char)>,
+	antialiasing: Antialiasing,
+	/// Fraction of `width`/`height` the text layer is rendered at, before being scaled up onto the image
+	render_scale: f32,
+	/// Rule-of-thirds cell (column, row) of the safe area the main block is moved into; it stays top-right if unset
+	block_cell: Option<(u32, u32)>,
+}
+
+/// A quote placed on its own in a corner, without source or balance
+#[derive(Clone, Copy, Debug)]
+struct QuoteBlock<'a> {
+	text: Option<&'a str>,
+	author: Option<&'a str>,
+	color: Option<&'a str>,
+}
+
+/// Where the extra quotes go, in order; the diagonal opposite the main block first. Top-right is the main block's.
+const EXTRA_QUOTE_CORNERS: [Corner; 3] = [Corner::BottomLeft, Corner::TopLeft, Corner::BottomRight];
+
+/// Where `generate_text_svg` anchored each element, as emitted into the SVG. Signed, as text too wide for the safe area
+/// starts left of the image.
+#[derive(Debug, Default)]
+struct TextLayout {
+	quote: (i64, i64),
+	author: Option<(i64, i64)>,
+	source: Option<(i64, i64)>,
+	balances: Vec<(i64, i64)>,
+	/// First baseline's left edge of each extra quote
+	extra_quotes: Vec<(i64, i64)>,
+	timestamp: Option<(i64, i64)>,
+	caption: Option<(i64, i64)>,
+}
+
+/// `--verbose-timing`: logs the time since the previous lap, under the stage's name. A no-op when disabled.
+struct StageTimer {
+	enabled: bool,
+	start: std::time::Instant,
+	last: std::time::Instant,
+}
+
+impl StageTimer {
+	fn new(enabled: bool) -> Self {
+		let now = std::time::Instant::now();
+		Self { enabled, start: now, last: now }
+	}
+
+	fn lap(&mut self, stage: &str) {
+		let now = std::time::Instant::now();
+		self.report(stage, now - self.last);
+		self.last = now;
+	}
+
+	/// For stages measured elsewhere, e.g. on another thread
+	fn report(&self, stage: &str, elapsed: std::time::Duration) {
+		if self.enabled {
+			v_utils::elog!("[timing] {stage}: {:.1}ms", elapsed.as_secs_f64() * 1000.0);
+		}
+	}
+
+	fn total(&self) {
+		self.report("total", self.start.elapsed());
+	}
+}
+
+/// A scratch file, removed when this goes out of scope; on the error paths too.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		if let Err(e) = std::fs::remove_file(&self.0)
+			&& e.kind() != std::io::ErrorKind::NotFound
+		{
+			warn!("Failed to remove {}: {e}", self.0.display());
+		}
+	}
+}
+
+/// Fill opacity (0.0-1.0) of each text element
+#[derive(Clone, Copy, Debug)]
+struct TextOpacity {
+	quote: f32,
+	author: f32,
+	balance: f32,
+}
+
+impl TextOpacity {
+	fn from_config(config: &AppConfig) -> Self {
+		let base = config.text_opacity.unwrap_or(1.0);
+		let resolve = |specific: Option<f32>| specific.unwrap_or(base).clamp(0.0, 1.0);
+		Self {
+			quote: resolve(config.quote_opacity),
+			author: resolve(config.author_opacity),
+			balance: resolve(config.balance_opacity),
+		}
+	}
 }
 
 fn get_cache_file_path() -> PathBuf {
@@ -99,6 +691,46 @@ fn get_lock_file_path() -> PathBuf {
 	v_utils::xdg_state_file!("wallpaper_generation.lock")
 }
 
+fn get_last_circle_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("last_circle.txt")
+}
+
+fn get_shuffle_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("shuffle_order.json")
+}
+
+fn get_playlist_position_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("playlist_position.json")
+}
+
+fn get_history_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("history.jsonl")
+}
+
+fn get_blacklist_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("blacklist.json")
+}
+
+fn get_last_applied_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("last_applied.txt")
+}
+
+fn get_vision_cache_file_path() -> PathBuf {
+	v_utils::xdg_cache_file!("vision.png")
+}
+
+fn get_image_hashes_file_path() -> PathBuf {
+	v_utils::xdg_cache_file!("image_hashes.json")
+}
+
+fn get_preview_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("preview.png")
+}
+
+fn get_dimmed_file_path() -> PathBuf {
+	v_utils::xdg_state_file!("dimmed.png")
+}
+
 fn get_supported_image_extensions() -> Vec<&'static str> {
 	// Based on image crate's supported formats
 	vec!["jpg", "jpeg", "png", "gif", "webp", "bmp", "ico", "tiff", "tif"]
@@ -145,12 +777,10 @@ fn get_newest_source_mtime(src_typ_dir: &Path) -> Result<std::time::SystemTime>
 			if path.file_name().map(|n| n.to_string_lossy().starts_with("output")).unwrap_or(false) {
 				continue;
 			}
-			if let Ok(metadata) = path.metadata() {
-				if let Ok(mtime) = metadata.modified() {
-					if mtime > newest {
-						newest = mtime;
-					}
-				}
+			if let Ok(mtime) = path.metadata().and_then(|metadata| metadata.modified())
+				&& mtime > newest
+			{
+				newest = mtime;
 			}
 		}
 	}
@@ -158,11 +788,13 @@ fn get_newest_source_mtime(src_typ_dir: &Path) -> Result<std::time::SystemTime>
 	Ok(newest)
 }
 
-fn regenerate_vision_if_needed() -> Result<PathBuf> {
+fn regenerate_vision_if_needed(force: bool) -> Result<PathBuf> {
 	let (vision_png, src_typ) = get_vision_paths()?;
 
 	// Check if we need to regenerate
-	let needs_regeneration = if vision_png.exists() {
+	let needs_regeneration = if force {
+		true
+	} else if vision_png.exists() {
 		let png_mtime = vision_png.metadata()?.modified()?;
 		let src_mtime = get_newest_source_mtime(&src_typ)?;
 		src_mtime > png_mtime
@@ -171,7 +803,11 @@ fn regenerate_vision_if_needed() -> Result<PathBuf> {
 	};
 
 	if needs_regeneration {
-		v_utils::log!("Vision sources are newer than output, regenerating...");
+		if force {
+			v_utils::log!("Regenerating vision document (forced)...");
+		} else {
+			v_utils::log!("Vision sources are newer than output, regenerating...");
+		}
 
 		// Create a temporary directory for compilation
 		let temp_dir = std::env::temp_dir().join("wallpaper_carousel_typst");
@@ -191,6 +827,15 @@ fn regenerate_vision_if_needed() -> Result<PathBuf> {
 			}
 		}
 
+		// Pages of a previous compilation would otherwise pass for this one's
+		for entry in std::fs::read_dir(&temp_dir)?.filter_map(|e| e.ok()) {
+			let name = entry.file_name();
+			let name = name.to_string_lossy();
+			if name.starts_with("output") && name.ends_with(".png") {
+				std::fs::remove_file(entry.path())?;
+			}
+		}
+
 		// Compile with typst
 		let output = ProcessCommand::new("typst")
 			.args(["compile", "--format", "png", "vision.typ", "output{n}.png"])
@@ -206,13 +851,17 @@ fn regenerate_vision_if_needed() -> Result<PathBuf> {
 			bail!("Error: More than 1 page generated. Vision document must be single-page.");
 		}
 
-		// Copy output to vision_png location (if writable) or to a cache location
 		let output_png = temp_dir.join("output1.png");
+		if !output_png.exists() {
+			bail!("Vision document produced no pages; is vision.typ empty?");
+		}
+
+		// Copy output to vision_png location (if writable) or to a cache location
 		let final_path = if std::fs::copy(&output_png, &vision_png).is_ok() {
 			vision_png
 		} else {
 			// Can't write to nix store, use a cache location
-			let cache_vision = v_utils::xdg_cache_file!("vision.png");
+			let cache_vision = get_vision_cache_file_path();
 			std::fs::copy(&output_png, &cache_vision)?;
 			cache_vision
 		};
@@ -224,15 +873,36 @@ fn regenerate_vision_if_needed() -> Result<PathBuf> {
 	}
 }
 
-fn find_next_image(current_path: &Path, backwards: bool, directory: Option<&Path>) -> Result<PathBuf> {
-	let parent = if let Some(dir) = directory {
-		dir
-	} else {
-		current_path.parent().context("Current image has no parent directory")?
-	};
+/// How the images of a directory are listed for picking the next one
+#[derive(Clone, Copy, Debug, Default)]
+struct ListingOptions {
+	/// With only one image around, hand it back instead of failing
+	allow_single: bool,
+	/// Skip images whose content is identical to one already listed
+	dedupe_by_hash: bool,
+	/// Step through the persisted shuffled order rather than the sorted one
+	shuffle: bool,
+}
+
+/// Where in which playlist Circle is. By index rather than path, as a playlist may list an image more than once.
+#[derive(Debug, Deserialize, Serialize)]
+struct PlaylistPosition {
+	playlist: PathBuf,
+	index: usize,
+}
 
-	// Get all image files in the directory
-	let mut image_files: Vec<PathBuf> = std::fs::read_dir(parent)?
+/// A shuffled order of a directory's images, kept until its contents change
+#[derive(Debug, Deserialize, Serialize)]
+struct ShuffleState {
+	directory: PathBuf,
+	/// Sorted, to tell whether the directory changed
+	files: Vec<PathBuf>,
+	order: Vec<PathBuf>,
+}
+
+/// All supported images directly inside `dir`, sorted for consistent ordering.
+fn list_images(dir: &Path, listing: ListingOptions) -> Result<Vec<PathBuf>> {
+	let mut image_files: Vec<PathBuf> = std::fs::read_dir(dir)?
 		.filter_map(|entry| entry.ok())
 		.map(|entry| entry.path())
 		.filter(|path| {
@@ -246,80 +916,269 @@ fn find_next_image(current_path: &Path, backwards: bool, directory: Option<&Path
 		.collect();
 
 	if image_files.is_empty() {
-		bail!("No images found in directory: {}", parent.display());
+		bail!("No images found in directory: {}", dir.display());
+	}
+
+	// Blacklisted images sit out, unless that would leave nothing at all
+	let blacklist = active_blacklist();
+	if !blacklist.is_empty() {
+		let allowed: Vec<PathBuf> = image_files.iter().filter(|path| !blacklist.contains(*path)).cloned().collect();
+		if allowed.is_empty() {
+			warn!("Every image in {} is blacklisted, ignoring the blacklist", dir.display());
+		} else {
+			image_files = allowed;
+		}
 	}
 
-	// Sort files for consistent ordering
 	image_files.sort();
+	if listing.dedupe_by_hash {
+		image_files = dedupe_by_hash(image_files)?;
+	}
+	Ok(image_files)
+}
 
-	if image_files.len() == 1 {
-		bail!("Only one image in directory: {}", parent.display());
+/// Blacklisted images, by the unix time (seconds) they're let back in at.
+fn read_blacklist() -> HashMap<PathBuf, u64> {
+	std::fs::read(get_blacklist_file_path())
+		.ok()
+		.and_then(|bytes| serde_json::from_slice(&bytes).ok())
+		.unwrap_or_default()
+}
+
+/// Images still blacklisted right now
+fn active_blacklist() -> HashSet<PathBuf> {
+	let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	read_blacklist().into_iter().filter(|&(_, until)| until > now).map(|(path, _)| path).collect()
+}
+
+/// Keeps `path` out of listings for `duration`, dropping expired entries while at it.
+fn blacklist_image(path: &Path, duration: std::time::Duration) -> Result<()> {
+	let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+	let mut blacklist = read_blacklist();
+	blacklist.retain(|_, &mut until| until > now);
+	blacklist.insert(path.to_path_buf(), now + duration.as_secs());
+	std::fs::write(get_blacklist_file_path(), serde_json::to_vec(&blacklist)?)?;
+	Ok(())
+}
+
+/// Drops images with the same content as an earlier one in the listing. Hashes are cached by path and mtime.
+fn dedupe_by_hash(image_files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+	let cache_path = get_image_hashes_file_path();
+	let mut cache: HashMap<PathBuf, (u64, u64)> = std::fs::read(&cache_path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()).unwrap_or_default();
+
+	let mut seen = HashSet::new();
+	let mut unique = Vec::with_capacity(image_files.len());
+	for path in image_files {
+		let mtime = path.metadata()?.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+		let hash = match cache.get(&path) {
+			Some(&(cached_mtime, hash)) if cached_mtime == mtime => hash,
+			_ => {
+				let mut hasher = DefaultHasher::new();
+				std::fs::read(&path)?.hash(&mut hasher);
+				let hash = hasher.finish();
+				cache.insert(path.clone(), (mtime, hash));
+				hash
+			}
+		};
+
+		if seen.insert(hash) {
+			unique.push(path);
+		} else {
+			info!("Skipping duplicate image: {}", path.display());
+		}
 	}
 
-	// Find current file index - if directory was provided and current file is not in it,
-	// start from the first or last image depending on direction
-	let current_index = image_files.iter().position(|p| p == current_path);
+	if let Some(parent) = cache_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	std::fs::write(&cache_path, serde_json::to_vec(&cache)?)?;
+	Ok(unique)
+}
 
-	// Calculate next index
-	let next_index = match current_index {
+/// Index of the image after (or before, if `backwards`) `current_path`, wrapping around at both ends.
+/// If `current_path` is not among `image_files`, starts from the first or last image depending on direction.
+fn next_index(image_files: &[PathBuf], current_path: &Path, backwards: bool) -> usize {
+	match image_files.iter().position(|p| p == current_path) {
 		Some(idx) =>
 			if backwards {
 				if idx == 0 { image_files.len() - 1 } else { idx - 1 }
 			} else {
 				(idx + 1) % image_files.len()
 			},
-		None => {
-			// Current file not in this directory, start from beginning or end
-			if backwards { image_files.len() - 1 } else { 0 }
-		}
-	};
+		None =>
+			if backwards {
+				image_files.len() - 1
+			} else {
+				0
+			},
+	}
+}
+
+/// Last built vision image, without checking whether it's stale.
+fn existing_vision_png() -> Result<PathBuf> {
+	let bundled = get_vision_paths().map(|(vision_png, _)| vision_png);
+	if let Ok(vision_png) = &bundled
+		&& vision_png.exists()
+	{
+		return Ok(vision_png.clone());
+	}
+
+	// Where `regenerate_vision_if_needed` puts it when the bundled location is read-only
+	let cache_vision = get_vision_cache_file_path();
+	if cache_vision.exists() {
+		return Ok(cache_vision);
+	}
+
+	bail!("No vision image has been built yet; run `generate` without --no-regen once")
+}
+
+/// Persisted permutation of `image_files` (sorted listing of `dir`), reshuffled whenever the listing changes.
+fn shuffled_order(dir: &Path, image_files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+	use rand::seq::SliceRandom as _;
+
+	let state_path = get_shuffle_file_path();
+	let state: Option<ShuffleState> = std::fs::read(&state_path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok());
+	if let Some(state) = state
+		&& state.directory == dir
+		&& state.files == image_files
+	{
+		return Ok(state.order);
+	}
 
-	Ok(image_files[next_index].clone())
+	v_utils::log!("Directory contents changed, reshuffling");
+	let mut order = image_files.clone();
+	order.shuffle(&mut rand::rng());
+	let state = ShuffleState {
+		directory: dir.to_path_buf(),
+		files: image_files,
+		order,
+	};
+	if let Some(parent) = state_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	std::fs::write(&state_path, serde_json::to_vec(&state)?)?;
+	Ok(state.order)
 }
 
-fn find_random_image(current_path: &Path, directory: Option<&Path>) -> Result<PathBuf> {
+fn find_next_image(current_path: &Path, backwards: bool, directory: Option<&Path>, listing: ListingOptions) -> Result<PathBuf> {
 	let parent = if let Some(dir) = directory {
 		dir
 	} else {
 		current_path.parent().context("Current image has no parent directory")?
 	};
 
-	// Get all image files in the directory
-	let mut image_files: Vec<PathBuf> = std::fs::read_dir(parent)?
-		.filter_map(|entry| entry.ok())
-		.map(|entry| entry.path())
-		.filter(|path| {
-			path.is_file()
-				&& path
-					.extension()
-					.and_then(|ext| ext.to_str())
-					.map(|ext| get_supported_image_extensions().contains(&ext.to_lowercase().as_str()))
-					.unwrap_or(false)
-		})
-		.collect();
+	let mut image_files = list_images(parent, listing)?;
 
-	if image_files.is_empty() {
-		bail!("No images found in directory: {}", parent.display());
+	if image_files.len() == 1 {
+		if listing.allow_single {
+			return Ok(image_files.remove(0));
+		}
+		bail!("Only one image in directory: {}", parent.display());
 	}
 
-	// Sort files for consistent ordering
-	image_files.sort();
-
-	// Remove current file from the list (only if it's in this directory)
-	image_files.retain(|p| p != current_path);
-
-	if image_files.is_empty() {
-		bail!("Only one image in directory: {}", parent.display());
+	if listing.shuffle {
+		image_files = shuffled_order(parent, image_files)?;
 	}
 
-	// Select a random image
-	let random_image = image_files.choose(&mut rand::rng()).context("Failed to select random image")?;
+	Ok(image_files[next_index(&image_files, current_path, backwards)].clone())
+}
 
-	Ok(random_image.clone())
+/// Existing entries of a playlist file: one path per line, relative ones resolved against the file's directory.
+/// Blank lines and `#` comments are ignored, missing files skipped with a warning.
+fn read_playlist(playlist: &Path) -> Result<Vec<PathBuf>> {
+	let content = std::fs::read_to_string(playlist).wrap_err_with(|| format!("Failed to read playlist {}", playlist.display()))?;
+	let base = playlist.parent().unwrap_or(Path::new("."));
+	let entries: Vec<PathBuf> = content
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| base.join(line))
+		.filter(|path| {
+			let exists = path.is_file();
+			if !exists {
+				warn!("Playlist entry not found, skipping: {}", path.display());
+			}
+			exists
+		})
+		.collect();
+	if entries.is_empty() {
+		bail!("No existing images in playlist {}", playlist.display());
+	}
+	Ok(entries)
 }
 
-fn check_and_handle_lock() -> Result<()> {
-	let lock_path = get_lock_file_path();
+/// Steps through the playlist from the persisted position (or picks at random, avoiding the current entry), saving the new one.
+fn find_playlist_image(playlist: &Path, backwards: bool, random: bool) -> Result<PathBuf> {
+	let entries = read_playlist(playlist)?;
+	let playlist = playlist.canonicalize()?;
+	let state_path = get_playlist_position_file_path();
+	let current = std::fs::read(&state_path)
+		.ok()
+		.and_then(|bytes| serde_json::from_slice::<PlaylistPosition>(&bytes).ok())
+		.filter(|position| position.playlist == playlist && position.index < entries.len())
+		.map(|position| position.index);
+
+	let len = entries.len();
+	let index = match (current, random) {
+		(current, true) => {
+			let candidates: Vec<usize> = (0..len).filter(|&i| len == 1 || Some(i) != current).collect();
+			*candidates.choose(&mut rand::rng()).context("Empty playlist")?
+		}
+		(None, false) if backwards => len - 1,
+		(None, false) => 0,
+		(Some(i), false) if backwards => (i + len - 1) % len,
+		(Some(i), false) => (i + 1) % len,
+	};
+
+	if let Some(parent) = state_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	std::fs::write(&state_path, serde_json::to_vec(&PlaylistPosition { playlist, index })?)?;
+	Ok(entries[index].clone())
+}
+
+/// [`find_random_image`] over a source picked at random, in proportion to the sources' weights. `explain` logs the odds.
+fn find_random_image_in_sources(current_path: &Path, sources: &[BackgroundSource], listing: ListingOptions, explain: bool) -> Result<PathBuf> {
+	let weight = |source: &BackgroundSource| source.weight.unwrap_or(1.0).max(0.0);
+	if explain {
+		let total: f32 = sources.iter().map(weight).sum();
+		for source in sources {
+			v_utils::elog!("Selection: source {} at {:.1}%", source.dir.display(), weight(source) / total * 100.0);
+		}
+	}
+	let source = sources.choose_weighted(&mut rand::rng(), weight).map_err(|e| eyre!("Can't pick from `sources`: {e}"))?;
+	v_utils::log!("Source: {}", source.dir.display());
+	find_random_image(current_path, Some(&source.dir), listing)
+}
+
+fn find_random_image(current_path: &Path, directory: Option<&Path>, listing: ListingOptions) -> Result<PathBuf> {
+	let parent = if let Some(dir) = directory {
+		dir
+	} else {
+		current_path.parent().context("Current image has no parent directory")?
+	};
+
+	let mut image_files = list_images(parent, listing)?;
+
+	if image_files.len() == 1 && listing.allow_single {
+		return Ok(image_files.remove(0));
+	}
+
+	// Remove current file from the list (only if it's in this directory)
+	image_files.retain(|p| p != current_path);
+
+	if image_files.is_empty() {
+		bail!("Only one image in directory: {}", parent.display());
+	}
+
+	// Select a random image
+	let random_image = image_files.choose(&mut rand::rng()).context("Failed to select random image")?;
+
+	Ok(random_image.clone())
+}
+
+fn check_and_handle_lock(policy: LockPolicy) -> Result<()> {
+	let lock_path = get_lock_file_path();
 
 	if lock_path.exists() {
 		// Read PID from lock file
@@ -332,6 +1191,24 @@ fn check_and_handle_lock() -> Result<()> {
 		}
 		let pid: i32 = pid_str.parse().context("Invalid PID in lock file")?;
 
+		match policy {
+			LockPolicy::Kill => {}
+			LockPolicy::Abort if process_alive(pid) => bail!("Another instance (PID: {pid}) is already running, exiting (lock_policy = \"abort\")"),
+			LockPolicy::Wait => {
+				v_utils::elog!("Found existing process (PID: {}), waiting for it to finish...", pid);
+				while lock_path.exists() && process_alive(pid) {
+					std::thread::sleep(std::time::Duration::from_millis(100));
+				}
+				let _ = std::fs::remove_file(&lock_path);
+				return Ok(());
+			}
+			// Holder is gone without cleaning up after itself
+			LockPolicy::Abort => {
+				std::fs::remove_file(&lock_path)?;
+				return Ok(());
+			}
+		}
+
 		// Try to kill the process
 		v_utils::elog!("Found existing process (PID: {}), killing it...", pid);
 		// SAFETY: We're sending SIGTERM to a process we know exists (read from lock file).
@@ -350,6 +1227,11 @@ fn check_and_handle_lock() -> Result<()> {
 	Ok(())
 }
 
+fn process_alive(pid: i32) -> bool {
+	// SAFETY: Signal 0 performs only the existence/permission check, nothing is delivered.
+	unsafe { libc::kill(pid, 0) == 0 }
+}
+
 fn create_lock() -> Result<()> {
 	let lock_path = get_lock_file_path();
 	if let Some(parent) = lock_path.parent() {
@@ -370,6 +1252,15 @@ fn remove_lock() -> Result<()> {
 	Ok(())
 }
 
+/// One-shot lock lifecycle: deal with any previous holder per `policy`, hold the lock for the duration of `f`, release it even if `f` fails.
+fn with_lock<T>(policy: LockPolicy, f: impl FnOnce() -> Result<T>) -> Result<T> {
+	check_and_handle_lock(policy)?;
+	create_lock()?;
+	let result = f();
+	remove_lock()?;
+	result
+}
+
 fn save_last_input(path: &Path) -> Result<()> {
 	let cache_path = get_cache_file_path();
 	if let Some(parent) = cache_path.parent() {
@@ -388,55 +1279,347 @@ fn load_last_input() -> Result<PathBuf> {
 	Ok(PathBuf::from(content.trim()))
 }
 
-fn generate_wallpaper(input_path: &Path, config: &AppConfig) -> Result<()> {
-	info!("Starting wallpaper generation for: {}", input_path.display());
+fn local_time() -> libc::tm {
+	// SAFETY: `time` accepts a null pointer.
+	local_time_at(unsafe { libc::time(std::ptr::null_mut()) })
+}
+
+fn local_time_at(time: libc::time_t) -> libc::tm {
+	// SAFETY: `localtime_r` only reads `time` and writes into the zero-initialized `tm` we own.
+	unsafe {
+		let mut tm: libc::tm = std::mem::zeroed();
+		libc::localtime_r(&time, &mut tm);
+		tm
+	}
+}
+
+/// `YYYY-MM-DD HH:MM` in local time
+fn format_local_time(unix_secs: u64) -> String {
+	let tm = local_time_at(unix_secs as libc::time_t);
+	format!("{:04}-{:02}-{:02} {:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min)
+}
+
+/// Current local time, formatted with strftime's `format`
+fn strftime_now(format: &str) -> Result<String> {
+	let tm = local_time();
+	let format = std::ffi::CString::new(format).context("Timestamp format contains a NUL byte")?;
+	let mut buf = vec![0u8; 256];
+	// SAFETY: `buf` is writable for the length we pass, `format` is NUL-terminated, and `tm` is initialized.
+	let len = unsafe { libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm) };
+	buf.truncate(len);
+	Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Seed that stays constant over a local calendar day.
+fn local_day_seed() -> u64 {
+	let tm = local_time();
+	(tm.tm_year as u64 + 1900) * 1000 + tm.tm_yday as u64
+}
+
+fn select_quote(quotes: &[Quote], mode: SelectionMode) -> Option<&Quote> {
+	match mode {
+		SelectionMode::Random => quotes.choose(&mut rand::rng()),
+		SelectionMode::Daily => quotes.choose(&mut StdRng::seed_from_u64(local_day_seed())),
+	}
+}
+
+/// Up to `count` distinct quotes, in the same manner as [`select_quote`]
+fn select_quotes(quotes: &[Quote], mode: SelectionMode, count: usize) -> Vec<&Quote> {
+	match mode {
+		SelectionMode::Random => quotes.choose_multiple(&mut rand::rng(), count).collect(),
+		SelectionMode::Daily => quotes.choose_multiple(&mut StdRng::seed_from_u64(local_day_seed()), count).collect(),
+	}
+}
+
+/// Quotes carrying all of `tags` (or any, with `any`). Errors rather than returning nothing, so a typo'd tag doesn't go unnoticed.
+fn filter_by_tags(quotes: &[Quote], tags: &[String], any: bool) -> Result<Vec<Quote>> {
+	let matched: Vec<Quote> = quotes
+		.iter()
+		.filter(|quote| match any {
+			true => tags.iter().any(|tag| quote.tags.contains(tag)),
+			false => tags.iter().all(|tag| quote.tags.contains(tag)),
+		})
+		.cloned()
+		.collect();
+	if matched.is_empty() {
+		bail!("No quotes tagged with {} of: {}", if any { "any" } else { "all" }, tags.join(", "));
+	}
+	Ok(matched)
+}
+
+/// Runs the configured balance commands, in display order. Failing ones are skipped with a warning.
+///
+/// `override_value` replaces them all with a single fixed value, styled and labeled as the first configured balance.
+fn collect_balances(config: &AppConfig, override_value: Option<&str>) -> Vec<BalanceBlock> {
+	let ordered = config.balance.as_ref().map(|b| b.ordered()).unwrap_or_default();
+
+	if let Some(value) = override_value {
+		let first = ordered.first();
+		let text = match first.and_then(|b| b.label.as_ref()) {
+			Some(label) => format!("{label}\n{value}"),
+			None => value.to_owned(),
+		};
+		v_utils::elog!("Balance (override):\n{}", text);
+		return vec![BalanceBlock {
+			text,
+			font_size: first.and_then(|b| b.font_size).unwrap_or(20),
+			color: first.and_then(|b| b.color.clone()),
+		}];
+	}
+
+	ordered
+		.into_iter()
+		.filter_map(|balance| {
+			let value = match balance.get_value() {
+				Ok(value) => value,
+				Err(e) => {
+					warn!("Balance command failed: {e}");
+					return None;
+				}
+			};
+			let value = match format_balance(balance, &value) {
+				Ok(formatted) => formatted,
+				Err(e) => {
+					warn!("{e}, showing it unformatted");
+					value
+				}
+			};
+			let text = if let Some(label) = &balance.label {
+				v_utils::elog!("{}:\n{}", label, value);
+				format!("{label}\n{value}")
+			} else {
+				v_utils::elog!("{}", value);
+				value
+			};
+			Some(BalanceBlock {
+				text,
+				font_size: balance.font_size.unwrap_or(20),
+				color: balance.color.clone(),
+			})
+		})
+		.collect()
+}
+
+/// `raw` with `balance`'s number formatting applied; as is if it has none. Errors if there is some, but `raw` isn't a number.
+fn format_balance(balance: &Balance, raw: &str) -> Result<String> {
+	if balance.prefix.is_none() && balance.suffix.is_none() && balance.decimals.is_none() && balance.thousands_separator.is_none() {
+		return Ok(raw.to_owned());
+	}
+	let value: f64 = raw.trim().parse().map_err(|_| eyre!("Balance output {raw:?} isn't a number"))?;
+	let number = format_number(value, balance.decimals, balance.thousands_separator.as_deref().unwrap_or(""));
+	// Sign goes in front of the prefix: -$5, not $-5
+	let (sign, number) = match number.strip_prefix('-') {
+		Some(magnitude) => ("-", magnitude),
+		None => ("", number.as_str()),
+	};
+	Ok(format!("{sign}{}{number}{}", balance.prefix.as_deref().unwrap_or(""), balance.suffix.as_deref().unwrap_or("")))
+}
+
+/// `value` rounded to `decimals` (shortest exact form if `None`), with `separator` between thousands of the integer part.
+fn format_number(value: f64, decimals: Option<usize>, separator: &str) -> String {
+	let digits = match decimals {
+		Some(decimals) => format!("{:.decimals$}", value.abs()),
+		None => value.abs().to_string(),
+	};
+	let (integer, fraction) = match digits.split_once('.') {
+		Some((integer, fraction)) => (integer, Some(fraction)),
+		None => (digits.as_str(), None),
+	};
+
+	let mut formatted = String::new();
+	// Rounding can leave nothing but zeroes, which shouldn't read as negative
+	if value < 0.0 && digits.chars().any(|c| matches!(c, '1'..='9')) {
+		formatted.push('-');
+	}
+	for (i, c) in integer.chars().enumerate() {
+		if i > 0 && (integer.len() - i) % 3 == 0 {
+			formatted.push_str(separator);
+		}
+		formatted.push(c);
+	}
+	if let Some(fraction) = fraction {
+		formatted.push('.');
+		formatted.push_str(fraction);
+	}
+	formatted
+}
+
+/// `input_path` is `None` for a synthesized background (`options.plain_background`), which has no file behind it.
+fn generate_wallpaper(input_path: Option<&Path>, config: &AppConfig, options: &GenerateOptions) -> Result<()> {
+	match input_path {
+		Some(path) => info!("Starting wallpaper generation for: {}", path.display()),
+		None => info!("Starting wallpaper generation for a plain background: {:?}", options.plain_background),
+	}
 
-	// Select a random quote
-	let quote = config.quotes.choose(&mut rand::rng()).context("No quotes configured")?;
+	// Select a quote from whichever pool is active right now
+	let hour = local_time().tm_hour as u32;
+	let quotes = config.active_quotes(hour);
+	if options.explain_selection {
+		let pool = match config.active_pool(hour) {
+			Some(pool) => format!("pool {:?}", pool.name.as_deref().unwrap_or("(unnamed)")),
+			None => "the default `quotes`".to_owned(),
+		};
+		v_utils::elog!("Selection: {} quote(s) in {pool}, which is active at {hour:02}:00", quotes.len());
+	}
+	let tagged;
+	let quotes = match options.tag.is_empty() {
+		true => quotes,
+		false => {
+			tagged = filter_by_tags(quotes, &options.tag, options.any_tag)?;
+			&tagged
+		}
+	};
+	if options.explain_selection && !options.tag.is_empty() {
+		v_utils::elog!(
+			"Selection: {} left carrying {} of tags {:?}",
+			quotes.len(),
+			if options.any_tag { "any" } else { "all" },
+			options.tag
+		);
+	}
+	let max_quotes = 1 + EXTRA_QUOTE_CORNERS.len();
+	let quote_count = config.quotes_per_wallpaper.unwrap_or(1);
+	if quote_count > max_quotes {
+		warn!("quotes_per_wallpaper is {quote_count}, but there are only {max_quotes} corners to put them in");
+	}
+	let selected = select_quotes(quotes, config.selection_mode.unwrap_or_default(), quote_count.clamp(1, max_quotes));
+	if options.explain_selection && !quotes.is_empty() {
+		if config.selection_mode.unwrap_or_default() == SelectionMode::Daily {
+			v_utils::elog!("Selection: daily, so the quotes are fixed for today by the date");
+		} else {
+			v_utils::elog!("Selection: uniform, so the main quote had a 1/{} ({:.1}%) chance", quotes.len(), 100.0 / quotes.len() as f64);
+		}
+	}
+	let (sampled, extras) = match selected.split_first() {
+		Some((&sampled, extras)) => (Some(sampled), extras),
+		None => (None, &[][..]),
+	};
+	// An image's own caption wins over the configured quotes
+	let sidecar = input_path.map(Sidecar::load).transpose()?.flatten();
+	let sidecar_quote = sidecar.as_ref().and_then(Sidecar::quote);
+	if let Some(path) = input_path
+		&& sidecar_quote.is_some()
+	{
+		v_utils::elog!("Using the quote from {}", Sidecar::path_for(path).display());
+	}
+	let quote = match &sidecar_quote {
+		Some(quote) => quote,
+		None => sampled.context("No quotes configured")?,
+	};
+	let credit = sidecar.as_ref().and_then(|s| s.credit.as_deref());
 	v_utils::elog!("Selected quote: {:?}", quote.text);
 	v_utils::elog!("Author: {:?}", quote.author);
+	for extra in extras {
+		v_utils::elog!("Extra quote: {:?}", extra.text);
+	}
+	let extra_quotes: Vec<QuoteBlock> = extras
+		.iter()
+		.map(|extra| QuoteBlock {
+			text: (!options.author_only).then_some(extra.text.as_str()),
+			author: extra.author.as_deref().filter(|_| !options.quote_only),
+			color: extra.color.as_deref(),
+		})
+		.collect();
 
-	// Get balance value if configured
-	let balance_text = if let Some(balance) = &config.balance {
-		match balance.get_value() {
-			Ok(value) =>
-				if let Some(label) = &balance.label {
-					v_utils::elog!("{}:\n{}", label, value);
-					Some(format!("{label}\n{value}"))
-				} else {
-					v_utils::elog!("{}", value);
-					Some(value)
-				},
-			Err(e) => {
-				warn!("Balance command failed: {e}");
-				None
-			}
-		}
-	} else {
-		None
+	// Get balance values if configured, in display order
+	let mut timer = StageTimer::new(options.verbose_timing);
+	let balances = match options.skip_balance {
+		true => Vec::new(),
+		false => collect_balances(config, options.balance_text.as_deref()),
 	};
+	timer.lap("balance commands");
 
 	v_utils::log!("Generating CSS...");
 
-	// Get display resolution from swaymsg
-	let (display_width, display_height) = get_display_resolution()?;
+	// Decode the image on a separate thread while querying sway; the decode is by far the slower of the two
+	let (decoded, all_displays, display_query_time) = std::thread::scope(|s| {
+		let decode = s.spawn(|| {
+			let start = std::time::Instant::now();
+			let decoded = input_path.map(|path| open_with_icc(path, options.frame));
+			(decoded, start.elapsed())
+		});
+		let start = std::time::Instant::now();
+		let outputs = get_outputs(options.outputs_from.as_deref());
+		(decode.join(), outputs, start.elapsed())
+	});
+	let (decoded, decode_time) = decoded.map_err(|_| eyre!("Image decoding thread panicked"))?;
+	let decoded = decoded.transpose()?;
+	timer.report("display query", display_query_time);
+	timer.report("decode", decode_time);
+	timer.lap("decode + display query, in parallel");
 
 	// Get all active displays to calculate safe area
-	let all_displays = get_all_active_displays()?;
+	let outputs = match &options.target_output {
+		Some(name) => only_output(all_displays?, name)?,
+		None => without_ignored(all_displays?, config.ignore_outputs.as_deref().unwrap_or_default()),
+	};
+
+	// Same inputs on the same displays make the same wallpaper, and that one is already up
+	let timestamp = config.timestamp.as_deref().map(strftime_now).transpose()?;
+	let fingerprint = generation_fingerprint(input_path, quote, extras, credit, &balances, &outputs, timestamp.as_deref(), options);
+	if options.skip_unchanged && std::fs::read_to_string(get_last_applied_file_path()).is_ok_and(|last| last.trim() == fingerprint.to_string()) {
+		v_utils::log!("Image, quotes, balance and displays are unchanged since the current wallpaper, skipping");
+		return Ok(());
+	}
+
+	let all_displays: Vec<(u32, u32)> = outputs.iter().filter_map(SwayOutput::effective_resolution).collect();
+	let rotation = match config.overlay_rotation.unwrap_or_default() {
+		OverlayRotation::None => 0,
+		OverlayRotation::Degrees(degrees) => degrees,
+		OverlayRotation::Auto => outputs.iter().filter(|o| o.current_mode.is_some()).map(SwayOutput::rotation).find(|&r| r != 0).unwrap_or(0),
+	};
+	let (display_width, display_height) = get_display_resolution(&all_displays)?;
 	v_utils::elog!("Found {} active display(s)", all_displays.len());
 	for (i, (w, h)) in all_displays.iter().enumerate() {
 		v_utils::elog!("  Display {}: {}x{} (ratio: {:.3})", i + 1, w, h, *w as f32 / *h as f32);
 	}
+	let (img, icc_profile) = match &options.plain_background {
+		Some(background) => (background.render(display_width, display_height), None),
+		None => decoded.context("Background image was not decoded")?,
+	};
 
-	// Save resized background image to temp location
-	let temp_bg_path = v_utils::xdg_state_file!("background_temp.png");
-	let img = image::open(input_path)?;
-	let resized_img = resize_fill(img, display_width, display_height);
+	// Save resized background image to temp location, per-process so concurrent generations don't read each other's
+	let temp_bg = TempFile(v_utils::xdg_state_file!("background_temp.png").with_file_name(format!("background_temp.{}.png", std::process::id())));
+	if icc_profile.is_some() {
+		info!("Source image has an embedded ICC profile, carrying it over to the output");
+	}
+	let source_dimensions = img.dimensions();
+	let mut resized_img = resize_fill(img, display_width, display_height, config.resize_filter.unwrap_or_default().filter_type());
+	if let Some(filter) = config.image_filter {
+		resized_img = apply_image_filter(resized_img, filter);
+	}
+	// Normalize the base the text sits on; no-ops unless configured
+	if let Some(brightness) = config.brightness
+		&& brightness != 0
+	{
+		resized_img = resized_img.brighten(brightness);
+	}
+	if let Some(contrast) = config.contrast
+		&& contrast != 0.0
+	{
+		resized_img = resized_img.adjust_contrast(contrast);
+	}
 	let (img_width, img_height) = resized_img.dimensions();
-	resized_img.save(&temp_bg_path)?;
+	timer.lap("resize");
+	// For --overlay-only the text goes onto nothing, which leaves just the layer
+	match options.overlay_only {
+		Some(_) => image::RgbaImage::new(img_width, img_height).save(&temp_bg.0)?,
+		None => resized_img.save(&temp_bg.0)?,
+	}
+	timer.lap("save temp background");
 
 	// Calculate safe area that will be visible on all monitors
-	let safe_area = calculate_safe_area(img_width, img_height, &all_displays);
+	let safe_area_displays = safe_area_displays(&all_displays, config.safe_area_min_width);
+	let safe_area = match options.plain_background {
+		// Nothing to crop away in a plain background
+		Some(_) => SafeArea {
+			x: 0,
+			y: 0,
+			width: img_width,
+			height: img_height,
+		},
+		None => calculate_safe_area(img_width, img_height, &safe_area_displays),
+	};
 	v_utils::elog!(
 		"Safe area: x={}, y={}, width={}, height={} ({:.1}% of image)",
 		safe_area.x,
@@ -445,158 +1628,966 @@ fn generate_wallpaper(input_path: &Path, config: &AppConfig) -> Result<()> {
 		safe_area.height,
 		(safe_area.width * safe_area.height) as f32 / (img_width * img_height) as f32 * 100.0
 	);
+	timer.lap("safe area");
+
+	let caption = match input_path {
+		Some(path) if config.caption_from_filename.unwrap_or(false) => {
+			let pattern = config.caption_pattern.as_deref().map(regex::Regex::new).transpose().wrap_err("Invalid caption_pattern")?;
+			filename_caption(path, pattern.as_ref())
+		}
+		_ => None,
+	};
+
+	// Rotated overlays are laid out in a turned frame, which the cells would have to be turned into too; not worth it. Extra
+	// quotes take the other corners, so the main block keeps to the top-right rather than wander into one of them.
+	if config.thirds_placement.unwrap_or(false) && !extra_quotes.is_empty() {
+		v_utils::elog!("thirds_placement: off, the extra quotes already take the other corners");
+	}
+	let block_cell = match config.thirds_placement.unwrap_or(false) && options.plain_background.is_none() && rotation == 0 && extra_quotes.is_empty() {
+		true => {
+			let cell = calmest_thirds_cell(&resized_img, &safe_area);
+			v_utils::elog!("thirds_placement: calmest cell is column {}, row {} of 3", cell.0 + 1, cell.1 + 1);
+			Some(cell)
+		}
+		false => None,
+	};
+
+	let mut scrim_color = config.scrim_color.as_deref().or(options.theme.map(Theme::scrim_color));
+	if config.auto_scrim.unwrap_or(false) {
+		let dark_text = config.text_color.is_none() && matches!(options.theme, Some(Theme::Light));
+		let text_area = match block_cell {
+			Some(cell) => thirds_cell(&safe_area, cell),
+			None => safe_area.clone(),
+		};
+		let needed = needs_scrim(&resized_img, &text_area, dark_text);
+		v_utils::elog!(
+			"auto_scrim: {}",
+			if needed {
+				"background is bright or busy under the text, adding a scrim"
+			} else {
+				"text reads fine without a scrim"
+			}
+		);
+		let fallback = if dark_text { Theme::Light.scrim_color() } else { Theme::Dark.scrim_color() };
+		scrim_color = needed.then(|| scrim_color.unwrap_or(fallback));
+	}
 
 	// Composite text onto background image
-	let text_padding = config.text_padding.unwrap_or(15);
-	let output_path = v_utils::xdg_state_file!("extended.png");
-	composite_text_on_image(&CompositeParams {
-		bg_image_path: &temp_bg_path,
-		output_path: &output_path,
-		text: &quote.text,
-		author: quote.author.as_deref(),
-		balance: balance_text.as_deref(),
-		width: img_width,
-		height: img_height,
-		safe_area: &safe_area,
-		text_padding,
-	})?;
+	let text_padding = config.text_padding.unwrap_or(Padding::Pixels(15)).resolve(safe_area.width, safe_area.height);
+	let composited = composite_text_on_image(
+		&CompositeParams {
+			bg_image_path: &temp_bg.0,
+			text: (!options.author_only).then_some(quote.text.as_str()),
+			author: quote.author.as_deref().filter(|_| !options.quote_only),
+			source: credit.or(quote.source.as_deref()).filter(|_| !options.quote_only),
+			balances: &balances,
+			width: img_width,
+			height: img_height,
+			safe_area: &safe_area,
+			text_padding,
+			quote_max_lines: config.quote_max_lines,
+			line_spacing: config.line_spacing.unwrap_or(1.2),
+			tab_width: config.tab_width.unwrap_or(4),
+			font_dirs: config.font_dirs.as_deref().unwrap_or_default(),
+			text_color: config.text_color.as_deref().or(options.theme.map(Theme::text_color)).unwrap_or("white"),
+			scrim_color,
+			quote_color: quote.color.as_deref(),
+			opacity: TextOpacity::from_config(config),
+			rotation,
+			extra_quotes: &extra_quotes,
+			timestamp: timestamp.as_deref(),
+			caption: caption.as_deref(),
+			overlay_image: config.overlay_image.as_ref(),
+			max_overlay_fraction: config.max_overlay_fraction,
+			quote_marks: config.quote_marks(),
+			antialiasing: Antialiasing::from_config(config),
+			render_scale: config.overlay_render_scale.unwrap_or(1.0).clamp(0.1, 1.0),
+			block_cell,
+		},
+		&mut timer,
+	);
+	drop(temp_bg);
+	let (composited, layout) = composited?;
+
+	if options.explain {
+		let quote_index = quotes.iter().position(|q| std::ptr::eq(q, quote));
+		explain_layout(
+			source_dimensions,
+			(img_width, img_height),
+			&safe_area_displays,
+			&safe_area,
+			text_padding,
+			quote_index,
+			quotes.len(),
+			&layout,
+		);
+	}
+
+	// Keep the source color profile, so the compositor renders it as the original
+	let output_format = config.output_format.unwrap_or_default();
+	let jpeg_quality = config.jpeg_quality.unwrap_or(90);
+	let webp_encoding = WebpEncoding::from_config(config);
+	if let Some(overlay_path) = &options.overlay_only {
+		save_image_with_icc(
+			&composited,
+			std::fs::File::create(overlay_path)?,
+			None,
+			OutputFormat::Png,
+			jpeg_quality,
+			webp_encoding,
+			config.output_dpi,
+		)?;
+		timer.lap("save");
+		timer.total();
+		v_utils::log!("Overlay layer written to {}", overlay_path.display());
+		return Ok(());
+	}
+	if let Some(output_to) = &options.output_to {
+		let output_path = output_to.with_extension(output_format.extension());
+		save_image_with_icc(
+			&composited,
+			std::fs::File::create(&output_path)?,
+			icc_profile.as_deref(),
+			output_format,
+			jpeg_quality,
+			webp_encoding,
+			config.output_dpi,
+		)?;
+		timer.lap("save");
+		timer.total();
+		v_utils::log!("Wallpaper written to {}", output_path.display());
+		return Ok(());
+	}
+	if let Some(sink) = &options.stdout_sink {
+		save_image_with_icc(&composited, &**sink, icc_profile.as_deref(), output_format, jpeg_quality, webp_encoding, config.output_dpi)?;
+		timer.lap("save");
+		timer.total();
+		v_utils::log!("Wallpaper written to stdout");
+		return Ok(());
+	}
+	let output_path = v_utils::xdg_state_file!("extended.png").with_extension(output_format.extension());
+	save_image_with_icc(
+		&composited,
+		std::fs::File::create(&output_path)?,
+		icc_profile.as_deref(),
+		output_format,
+		jpeg_quality,
+		webp_encoding,
+		config.output_dpi,
+	)?;
+	timer.lap("save");
 
-	// Set wallpaper using swaymsg
-	ProcessCommand::new("swaymsg")
-		.args(["output", "*", "background", output_path.to_str().unwrap(), "fill"])
-		.output()?;
+	// Set wallpaper using swaymsg (or the configured command)
+	set_wallpaper(&output_path, config, options.target_output.as_deref())?;
+	timer.lap("set wallpaper");
+	timer.total();
 
 	v_utils::log!("Wallpaper set to {}", output_path.display());
 
+	if let Err(e) = std::fs::write(get_last_applied_file_path(), fingerprint.to_string()) {
+		warn!("Failed to record what the wallpaper was made from: {e}");
+	}
+	if let Some(path) = input_path
+		&& !options.rerender
+		&& let Err(e) = record_history(path, &quote.text)
+	{
+		warn!("Failed to record history: {e}");
+	}
+
+	if options.also_lockscreen {
+		let lockscreen_path = config.lockscreen_image_path.as_deref().context("--also-lockscreen requires `lockscreen_image_path` in config")?;
+		if let Some(parent) = lockscreen_path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		// Copy rather than link: the output gets rewritten in place by the next run, possibly while the locker reads it
+		std::fs::copy(&output_path, lockscreen_path).wrap_err_with(|| format!("Failed to copy wallpaper to {}", lockscreen_path.display()))?;
+		v_utils::log!("Lockscreen image updated: {}", lockscreen_path.display());
+	}
+
 	Ok(())
 }
 
-fn handle_next_command(backwards: bool, random: bool, directory: Option<PathBuf>) -> Result<()> {
-	info!("Circle command: backwards={backwards}, random={random}, directory={directory:?}");
-
-	// Load the current image path
-	let current_path = load_last_input()?;
+/// A random photo from `generate_background_dir` other than `current_path`, or else the vision image
+fn pick_generate_background(config: &AppConfig, current_path: &Path, no_regen: bool, force_regen: bool) -> Result<PathBuf> {
+	match &config.generate_background_dir {
+		Some(dir) => {
+			let listing = ListingOptions {
+				allow_single: true,
+				dedupe_by_hash: config.dedupe_by_hash.unwrap_or(false),
+				..Default::default()
+			};
+			let photo = find_random_image(current_path, Some(dir), listing)?;
+			v_utils::log!("Using background photo: {}", photo.display());
+			Ok(photo)
+		}
+		None => {
+			// Get the bundled vision image path, regenerating if needed
+			let vision_path = if no_regen { existing_vision_png()? } else { regenerate_vision_if_needed(force_regen)? };
+			v_utils::log!("Using vision image: {}", vision_path.display());
+			Ok(vision_path)
+		}
+	}
+}
 
-	// Determine which directory to use
-	let target_dir = if let Some(ref dir) = directory {
-		dir.as_path()
-	} else {
-		current_path.parent().context("Current image has no parent directory")?
-	};
-	v_utils::log!("Directory: {}", target_dir.display());
+/// Checksum of what a wallpaper is made from: the image (path and mtime), the quotes, the balance output, the formatted
+/// timestamp, the display layout and the per-run options. A new image always changes it, so it only ever matches when the
+/// image that's up comes around again: a single-image directory, or `extend` of the current one. Config edits aren't in it;
+/// `refresh` picks those up. It's written to disk, so it's a crc32 of plain text rather than `DefaultHasher`, whose output
+/// can change between Rust releases.
+#[allow(clippy::too_many_arguments)]
+fn generation_fingerprint(
+	input_path: Option<&Path>,
+	quote: &Quote,
+	extras: &[&Quote],
+	credit: Option<&str>,
+	balances: &[BalanceBlock],
+	outputs: &[SwayOutput],
+	timestamp: Option<&str>,
+	options: &GenerateOptions,
+) -> u32 {
+	let mtime = input_path
+		.and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+		.and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok());
+	let mut inputs = vec![
+		format!(
+			"image {} {}",
+			input_path.map(|path| path.display().to_string()).unwrap_or_default(),
+			mtime.map_or(0, |mtime| mtime.as_nanos())
+		),
+		format!("credit {}", credit.unwrap_or_default()),
+		format!("timestamp {}", timestamp.unwrap_or_default()),
+	];
+	for quote in std::iter::once(quote).chain(extras.iter().copied()) {
+		let fields = [Some(quote.text.as_str()), quote.author.as_deref(), quote.source.as_deref(), quote.color.as_deref()];
+		inputs.push(format!("quote {}", fields.map(Option::unwrap_or_default).join("\t")));
+	}
+	inputs.extend(balances.iter().map(|balance| format!("balance {}", balance.text)));
+	for output in outputs {
+		let (width, height) = output.effective_resolution().unwrap_or_default();
+		inputs.push(format!("output {} {width}x{height} {}deg {}x", output.name, output.rotation(), output.scale.unwrap_or(1.0)));
+	}
+	inputs.push(format!(
+		"options {:?}",
+		(
+			options.frame,
+			options.theme,
+			options.author_only,
+			options.quote_only,
+			options.plain_background,
+			&options.target_output
+		)
+	));
+	crc32(inputs.join("\n").as_bytes())
+}
 
-	// Find next image
-	let next_path = if random {
-		find_random_image(&current_path, directory.as_deref())?
-	} else {
-		find_next_image(&current_path, backwards, directory.as_deref())?
+/// The caption `caption_from_filename` puts under `path`: its file stem, or what `pattern` picks out of it, with underscores
+/// as spaces. `None` if that leaves nothing, or `pattern` doesn't match.
+fn filename_caption(path: &Path, pattern: Option<&regex::Regex>) -> Option<String> {
+	let stem = path.file_stem()?.to_string_lossy();
+	let picked = match pattern {
+		Some(pattern) => {
+			let captures = pattern.captures(&stem)?;
+			captures.get(1).or(captures.get(0))?.as_str().to_owned()
+		}
+		None => stem.into_owned(),
 	};
-	v_utils::log!("Next image: {}", next_path.display());
+	let caption = picked.replace('_', " ").trim().to_owned();
+	(!caption.is_empty()).then_some(caption)
+}
 
-	// Check for existing lock and kill if necessary
-	check_and_handle_lock()?;
+/// Records this Circle's timestamp, and whether the previous one was less than `debounce_ms` ago.
+fn circle_debounced(debounce_ms: u64) -> Result<bool> {
+	let path = get_last_circle_file_path();
+	let now_ms = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis() as u64;
+	let last_ms: Option<u64> = std::fs::read_to_string(&path).ok().and_then(|s| s.trim().parse().ok());
 
-	// Set wallpaper immediately with the original next image (sway handles resizing)
-	ProcessCommand::new("swaymsg").args(["output", "*", "background", next_path.to_str().unwrap(), "fill"]).output()?;
-	v_utils::log!("Wallpaper set to: {}", next_path.display());
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	std::fs::write(&path, now_ms.to_string())?;
 
-	// Save the next path to cache
-	save_last_input(&next_path)?;
+	Ok(last_ms.is_some_and(|last_ms| now_ms.saturating_sub(last_ms) < debounce_ms))
+}
 
-	// Spawn a separate background process to generate text overlay
-	// We use std::process::Command instead of thread::spawn because when the main
-	// process exits, spawned threads are killed. A separate process continues independently.
-	let current_exe = std::env::current_exe()?;
-	ProcessCommand::new(current_exe)
-		.arg("extend")
-		.arg(&next_path)
-		.stdin(std::process::Stdio::null())
-		.stdout(std::process::Stdio::null())
-		.stderr(std::process::Stdio::null())
-		.spawn()?;
+#[allow(clippy::too_many_arguments)]
+fn explain_layout(
+	(source_width, source_height): (u32, u32),
+	(img_width, img_height): (u32, u32),
+	displays: &[(u32, u32)],
+	safe_area: &SafeArea,
+	text_padding: u32,
+	quote_index: Option<usize>,
+	quote_count: usize,
+	layout: &TextLayout,
+) {
+	let mut report = String::from("Layout explanation\n");
+	report.push_str(&format!("  input:      {source_width}x{source_height}\n"));
+	report.push_str(&format!("  resized to: {img_width}x{img_height}\n"));
+	report.push_str("  visible part of the image per display (fill mode):\n");
+	for (i, &display) in displays.iter().enumerate() {
+		let crop = crop_window(img_width, img_height, display);
+		report.push_str(&format!(
+			"    {}. {}x{}: x={}..{}, y={}..{}\n",
+			i + 1,
+			display.0,
+			display.1,
+			crop.x,
+			crop.x + crop.width,
+			crop.y,
+			crop.y + crop.height
+		));
+	}
+	report.push_str(&format!(
+		"  safe area (intersection): x={}..{}, y={}..{} ({:.1}% of image)\n",
+		safe_area.x,
+		safe_area.x + safe_area.width,
+		safe_area.y,
+		safe_area.y + safe_area.height,
+		(safe_area.width * safe_area.height) as f32 / (img_width * img_height) as f32 * 100.0
+	));
+	report.push_str(&format!("  text padding: {text_padding}px\n"));
+	match quote_index {
+		Some(i) => report.push_str(&format!("  quote: #{i} of {quote_count}\n")),
+		None => report.push_str(&format!("  quote: not among the {quote_count} active ones\n")),
+	}
+	report.push_str(&format!("  quote at:  x={}, y={} (first baseline)\n", layout.quote.0, layout.quote.1));
+	if let Some((x, y)) = layout.author {
+		report.push_str(&format!("  author at: x={x}, y={y} (right edge)\n"));
+	}
+	if let Some((x, y)) = layout.source {
+		report.push_str(&format!("  source at: x={x}, y={y} (right edge)\n"));
+	}
+	if let Some((x, y)) = layout.timestamp {
+		report.push_str(&format!("  timestamp at: x={x}, y={y} (right edge)\n"));
+	}
+	if let Some((x, y)) = layout.caption {
+		report.push_str(&format!("  caption at: x={x}, y={y} (right edge)\n"));
+	}
+	for (i, (x, y)) in layout.balances.iter().enumerate() {
+		report.push_str(&format!("  balance {} at: x={x}, y={y}\n", i + 1));
+	}
+	for (i, (x, y)) in layout.extra_quotes.iter().enumerate() {
+		report.push_str(&format!("  extra quote {} at: x={x}, y={y} (first baseline)\n", i + 1));
+	}
+	v_utils::elog!("{}", report.trim_end());
+}
 
-	v_utils::log!("Text overlay generation started in background...");
+/// One wallpaper that has been set, as kept in the history file (one JSON object per line)
+#[derive(Debug, Deserialize, Serialize)]
+struct HistoryEntry {
+	/// Unix seconds
+	timestamp: u64,
+	image: PathBuf,
+	quote: String,
+}
 
+fn record_history(image: &Path, quote: &str) -> Result<()> {
+	let entry = HistoryEntry {
+		timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs(),
+		image: image.to_path_buf(),
+		quote: quote.to_owned(),
+	};
+	let path = get_history_file_path();
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+	writeln!(file, "{}", serde_json::to_string(&entry)?)?;
 	Ok(())
 }
 
-fn run() -> Result<()> {
-	let args = Args::parse();
-
-	// Handle subcommands
-	match args.command {
-		Command::Circle {
-			forward,
-			backwards,
-			random,
-			directory,
-		} => {
-			// Require at least one flag
-			if !forward && !backwards && !random {
-				bail!("Please specify either --forward, --backwards, or --random");
+/// Everything the tool writes to keep track of things between runs. Not the generated `extended.<ext>` nor its copy at
+/// `lockscreen_image_path`: those are what's on screen (and what `dim` starts from), and swaylock may be pointed at the copy.
+fn cache_and_state_paths() -> Vec<PathBuf> {
+	let mut paths = vec![
+		get_cache_file_path(),
+		get_lock_file_path(),
+		get_last_circle_file_path(),
+		get_shuffle_file_path(),
+		get_playlist_position_file_path(),
+		get_history_file_path(),
+		get_blacklist_file_path(),
+		get_last_applied_file_path(),
+		get_vision_cache_file_path(),
+		get_image_hashes_file_path(),
+		v_utils::xdg_cache_file!("quotes_url"),
+		get_preview_file_path(),
+		get_dimmed_file_path(),
+		std::env::temp_dir().join("wallpaper_carousel_typst"),
+	];
+
+	// Per-process temp backgrounds, left behind by runs that were killed
+	let leftovers = [
+		(get_lock_file_path().parent().map(Path::to_path_buf), "background_temp."),
+		(Some(std::env::temp_dir()), "wallpaper_carousel_benchmark."),
+	];
+	for (dir, prefix) in leftovers {
+		for entry in dir.and_then(|dir| std::fs::read_dir(dir).ok()).into_iter().flatten().flatten() {
+			if entry.file_name().to_string_lossy().starts_with(prefix) {
+				paths.push(entry.path());
 			}
-			// backwards takes precedence if both are somehow set, then random
-			handle_next_command(backwards, random, directory)
 		}
-		Command::Extend { input } => {
-			// Load config from CLI flags
-			let config = AppConfig::try_build(args.settings)?;
+	}
+	paths
+}
 
-			// Check and handle existing lock (kill previous background process if running)
-			check_and_handle_lock()?;
+fn clean() -> Result<()> {
+	// Paths are only ever ours, but a wallpaper kept in the state directory still mustn't go
+	let current_input = load_last_input().ok();
+	let mut removed = 0;
+	for path in cache_and_state_paths() {
+		if current_input.as_deref() == Some(path.as_path()) {
+			v_utils::log!("Leaving {} alone, it's the current wallpaper's image", path.display());
+			continue;
+		}
+		let result = match path.is_dir() {
+			true => std::fs::remove_dir_all(&path),
+			false => std::fs::remove_file(&path),
+		};
+		match result {
+			Ok(()) => {
+				v_utils::log!("Removed {}", path.display());
+				removed += 1;
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(e).wrap_err_with(|| format!("Failed to remove {}", path.display())),
+		}
+	}
+	if removed == 0 {
+		v_utils::log!("Nothing to clean");
+	}
+	Ok(())
+}
 
-			// Create lock for this process
-			create_lock()?;
+fn print_stats() -> Result<()> {
+	let path = get_history_file_path();
+	let content = std::fs::read_to_string(&path).wrap_err_with(|| format!("No history recorded yet at {}", path.display()))?;
+	let entries: Vec<HistoryEntry> = content.lines().filter(|l| !l.trim().is_empty()).filter_map(|l| serde_json::from_str(l).ok()).collect();
+
+	/// Per key: (times seen, most recent timestamp)
+	fn tally<'a>(keys: impl Iterator<Item = (&'a str, u64)>) -> Vec<(&'a str, usize, u64)> {
+		let mut counts: HashMap<&str, (usize, u64)> = HashMap::new();
+		for (key, timestamp) in keys {
+			let (count, last) = counts.entry(key).or_default();
+			*count += 1;
+			*last = (*last).max(timestamp);
+		}
+		let mut sorted: Vec<_> = counts.into_iter().map(|(key, (count, last))| (key, count, last)).collect();
+		sorted.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)));
+		sorted
+	}
 
-			// Determine input path: use provided arg or load from cache
-			let input_path = match input {
-				Some(path) => path,
-				None => load_last_input()?,
-			};
+	println!("{} wallpapers set", entries.len());
 
-			// Generate wallpaper
-			let result = generate_wallpaper(&input_path, &config);
+	println!("\nImages:");
+	let images = tally(entries.iter().map(|e| (e.image.to_str().unwrap_or("<non-utf8 path>"), e.timestamp)));
+	for (image, count, last) in images {
+		println!("  {count:>4}  {}  {image}", format_local_time(last));
+	}
 
-			// Remove lock
-			remove_lock()?;
+	println!("\nQuotes:");
+	let quotes = tally(entries.iter().map(|e| (e.quote.as_str(), e.timestamp)));
+	for (quote, count, last) in quotes {
+		println!("  {count:>4}  {}  {}", format_local_time(last), quote.replace('\n', " / "));
+	}
+	Ok(())
+}
 
-			// Save the input path to cache for next time
-			save_last_input(&input_path)?;
+/// Our own executable, if it can still be run. Under AppImage or after a Nix store gc it may be gone or replaced.
+fn respawnable_exe() -> Result<PathBuf> {
+	use std::os::unix::fs::PermissionsExt as _;
 
-			result
-		}
-		Command::Generate => {
-			// Load config from CLI flags
-			let config = AppConfig::try_build(args.settings)?;
+	let exe = std::env::current_exe()?;
+	// Linux reports a replaced/removed executable as "<path> (deleted)", which doesn't exist
+	let metadata = exe.metadata().wrap_err_with(|| format!("{} is gone", exe.display()))?;
+	if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+		bail!("{} is not executable", exe.display());
+	}
+	Ok(exe)
+}
 
-			// Check and handle existing lock (kill previous background process if running)
-			check_and_handle_lock()?;
+/// `circle --mode random-per-output`: every active output gets its own random pick, set directly without an overlay.
+fn circle_random_per_output(directory: Option<PathBuf>, listing: ListingOptions, options: &GenerateOptions) -> Result<()> {
+	let current_path = load_last_input()?;
+	let outputs: Vec<SwayOutput> = get_outputs(options.outputs_from.as_deref())?.into_iter().filter(|o| o.current_mode.is_some()).collect();
+	if outputs.is_empty() {
+		bail!("No active outputs found");
+	}
 
-			// Create lock for this process
-			create_lock()?;
+	// Each pick excludes the previous one, so neighbouring outputs don't end up with the same image
+	let mut previous = current_path;
+	let mut first = None;
+	for output in &outputs {
+		let path = find_random_image(&previous, directory.as_deref(), listing)?;
+		set_output_wallpaper(&output.name, &path)?;
+		v_utils::log!("{}: {}", output.name, path.display());
+		first.get_or_insert_with(|| path.clone());
+		previous = path;
+	}
 
-			// Get the bundled vision image path, regenerating if needed
-			let vision_path = regenerate_vision_if_needed()?;
-			v_utils::log!("Using vision image: {}", vision_path.display());
+	// Later `circle`s continue from the first output's image
+	if let Some(first) = first {
+		save_last_input(&first)?;
+	}
+	Ok(())
+}
+
+/// How many undecodable images in a row circle skips past before giving up
+const MAX_UNDECODABLE_SKIPS: usize = 16;
+
+/// Fully decodes `path` (the first frame, for animations) and throws the pixels away.
+fn ensure_decodable(path: &Path) -> Result<()> {
+	image::ImageReader::open(path)?.with_guessed_format()?.decode()?;
+	Ok(())
+}
+
+/// Moves on to the next image. `false` if it didn't, because the circle before it was too recent.
+fn handle_next_command(
+	backwards: bool,
+	random: bool,
+	directory: Option<PathBuf>,
+	playlist: Option<&Path>,
+	listing: ListingOptions,
+	config: &AppConfig,
+	options: &GenerateOptions,
+) -> Result<bool> {
+	info!("Circle command: backwards={backwards}, random={random}, directory={directory:?}, playlist={playlist:?}, listing={listing:?}");
+
+	let debounce_ms = config.circle_debounce_ms.unwrap_or(100);
+	if debounce_ms > 0 && circle_debounced(debounce_ms)? {
+		v_utils::log!("Circle fired again within {debounce_ms}ms, ignoring");
+		return Ok(false);
+	}
+
+	// An image that doesn't decode would leave swaymsg showing nothing, so those get skipped, moving on from them as if they were current
+	let mut undecodable: Option<PathBuf> = None;
+	let mut skipped = 0;
+	let next_path = loop {
+		let candidate = match playlist {
+			Some(playlist) => {
+				v_utils::log!("Playlist: {}", playlist.display());
+				find_playlist_image(playlist, backwards, random)?
+			}
+			None => {
+				// Load the current image path
+				let current_path = match undecodable.take() {
+					Some(path) => path,
+					None => load_last_input()?,
+				};
+
+				// Determine which directory to use
+				let target_dir = if let Some(ref dir) = directory {
+					dir.as_path()
+				} else {
+					current_path.parent().context("Current image has no parent directory")?
+				};
+				v_utils::log!("Directory: {}", target_dir.display());
+
+				// Find next image
+				if random {
+					match (&directory, config.sources.as_deref()) {
+						(None, Some(sources)) if !sources.is_empty() => find_random_image_in_sources(&current_path, sources, listing, options.explain_selection)?,
+						_ => find_random_image(&current_path, directory.as_deref(), listing)?,
+					}
+				} else {
+					find_next_image(&current_path, backwards, directory.as_deref(), listing)?
+				}
+			}
+		};
+		match ensure_decodable(&candidate) {
+			Ok(()) => break candidate,
+			Err(e) if skipped < MAX_UNDECODABLE_SKIPS => {
+				warn!("Skipping {}, it doesn't decode: {e}", candidate.display());
+				skipped += 1;
+				undecodable = Some(candidate);
+			}
+			Err(e) => return Err(e.wrap_err(format!("Gave up after {skipped} images in a row that don't decode"))),
+		}
+	};
+	v_utils::log!("Next image: {}", next_path.display());
+
+	if config.circle_immediate.unwrap_or(false) {
+		// Slower to react, but the plain image never shows up
+		let result = with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(Some(&next_path), config, options));
+		save_last_input(&next_path)?;
+		return result.map(|()| true);
+	}
+
+	// Check for existing lock and kill (or wait, or abort) if necessary
+	check_and_handle_lock(config.lock_policy.unwrap_or_default())?;
+
+	// Set wallpaper immediately with the original next image (sway handles resizing)
+	set_wallpaper(&next_path, config, options.target_output.as_deref())?;
+	v_utils::log!("Wallpaper set to: {}", next_path.display());
+
+	// Save the next path to cache
+	save_last_input(&next_path)?;
+
+	// Spawn a separate background process to generate text overlay
+	// We use std::process::Command instead of thread::spawn because when the main
+	// process exits, spawned threads are killed. A separate process continues independently.
+	let spawned = respawnable_exe().and_then(|current_exe| {
+		let mut command = ProcessCommand::new(current_exe);
+		command.args(top_level_args()).arg("extend").arg(&next_path).args(options.to_args());
+		if let Some(output) = &options.target_output {
+			command.arg("--output").arg(output);
+		}
+		command
+			.stdin(std::process::Stdio::null())
+			.stdout(std::process::Stdio::null())
+			.stderr(std::process::Stdio::null())
+			.spawn()
+			.map_err(Into::into)
+	});
+	if let Err(e) = spawned {
+		v_utils::log!("Can't respawn ourselves for the overlay ({e}), generating it here instead");
+		return with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(Some(&next_path), config, options)).map(|()| true);
+	}
+
+	v_utils::log!("Text overlay generation started in background...");
+
+	Ok(true)
+}
+
+/// Points fd 1 at stderr for the rest of the process, handing back a handle to the original stdout.
+fn divert_stdout_to_stderr() -> Result<std::fs::File> {
+	use std::os::fd::FromRawFd as _;
+
+	std::io::stdout().flush()?;
+	// SAFETY: dup/dup2 only duplicate descriptors. The fd from `dup` is fresh, so the returned File is its sole owner.
+	unsafe {
+		let image_fd = libc::dup(libc::STDOUT_FILENO);
+		if image_fd < 0 {
+			bail!("Failed to duplicate stdout: {}", std::io::Error::last_os_error());
+		}
+		if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+			bail!("Failed to redirect stdout to stderr: {}", std::io::Error::last_os_error());
+		}
+		Ok(std::fs::File::from_raw_fd(image_fd))
+	}
+}
+
+fn run() -> Result<()> {
+	let args = Args::parse();
+
+	// With --stdout, image bytes own the real stdout; everything human-readable goes to stderr
+	let mut command = args.command;
+	let mut options = command.as_mut().and_then(Command::generate_options).map(std::mem::take).unwrap_or_default();
+	if matches!(
+		command,
+		Some(Command::Extend { stdout: true, .. } | Command::Apply { stdout: true, .. } | Command::Generate { stdout: true, .. } | Command::Refresh { stdout: true, .. })
+	) {
+		options.stdout_sink = Some(Arc::new(divert_stdout_to_stderr()?));
+	}
+	// Load config from CLI flags, folding in the flags that extend rather than override config values
+	let settings = args.settings;
+	let font_dirs = args.font_dir;
+	let strict_config = args.strict_config;
+	let load_config = move || -> Result<AppConfig> {
+		let mut config = AppConfig::try_build_portable(settings, strict_config)?;
+		if !font_dirs.is_empty() {
+			config.font_dirs.get_or_insert_default().extend(font_dirs);
+		}
+		Ok(config)
+	};
+
+	if args.config_check {
+		return config_check(&load_config()?);
+	}
+
+	// Handle subcommands
+	let Some(command) = command else {
+		bail!("No subcommand given, see --help");
+	};
+	match command {
+		Command::Circle {
+			forward,
+			backwards,
+			random,
+			allow_single,
+			shuffle,
+			mode,
+			playlist,
+			output,
+			blacklist,
+			clear_blacklist,
+			directory,
+			..
+		} => {
+			if clear_blacklist {
+				if let Err(e) = std::fs::remove_file(get_blacklist_file_path())
+					&& e.kind() != std::io::ErrorKind::NotFound
+				{
+					return Err(e.into());
+				}
+				v_utils::log!("Blacklist cleared");
+				return Ok(());
+			}
+			// Require at least one flag
+			// `--shuffle` and `--blacklist` alone mean forwards, and per-output mode is random by definition
+			if !forward && !backwards && !random && !shuffle && !blacklist && mode == CircleMode::Same {
+				bail!("Please specify either --forward, --backwards, --random, --shuffle, --blacklist or --mode random-per-output");
+			}
+			// backwards takes precedence if both are somehow set, then random
+			let config = load_config()?;
+			let listing = ListingOptions {
+				allow_single,
+				dedupe_by_hash: config.dedupe_by_hash.unwrap_or(false),
+				shuffle,
+			};
+			if mode == CircleMode::RandomPerOutput {
+				return circle_random_per_output(directory, listing, &options);
+			}
+			options.target_output = output;
+			// Moving on first, while the current image still has its place in the listing to move on from
+			let blacklisted = if blacklist { Some(load_last_input()?) } else { None };
+			let switched = handle_next_command(backwards, random, directory, playlist.as_deref(), listing, &config, &options)?;
+			if switched && let Some(path) = blacklisted {
+				let hours = config.blacklist_hours.unwrap_or(24);
+				blacklist_image(&path, std::time::Duration::from_secs(hours * 3600))?;
+				v_utils::log!("Blacklisted {} for {hours}h", path.display());
+			}
+			Ok(())
+		}
+		Command::Extend { input, output, .. } => {
+			let config = load_config()?;
+			options.target_output = output;
+
+			// Determine input path: use provided arg or load from cache
+			let input_path = match input {
+				Some(path) => path,
+				None => load_last_input()?,
+			};
+			apply(&input_path, &config, &options)
+		}
+		Command::Apply { image, output, .. } => {
+			let config = load_config()?;
+			options.target_output = output;
+			apply(&image, &config, &options)
+		}
+		Command::Refresh { .. } => {
+			let config = load_config()?;
+			let input_path = load_last_input()?;
+			v_utils::log!("Refreshing: {}", input_path.display());
+
+			// Position in the directory stays as is, so there is nothing to save back, nor anything new for the history
+			options.rerender = true;
+			with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(Some(&input_path), &config, &options))
+		}
+		Command::Generate {
+			no_regen,
+			force_regen,
+			solid,
+			gradient,
+			out_dir,
+			count,
+			output,
+			..
+		} => {
+			let config = load_config()?;
+			options.target_output = output;
+
+			let plain = solid.map(PlainBackground::Solid).or(gradient.map(|(top, bottom)| PlainBackground::Gradient(top, bottom)));
+			options.plain_background = plain;
+			// Nothing on disk to point to, so a plain background neither becomes the last input nor needs one
+
+			if let Some(out_dir) = out_dir {
+				std::fs::create_dir_all(&out_dir).wrap_err_with(|| format!("Failed to create {}", out_dir.display()))?;
+				let count = count.unwrap_or(1);
+				let digits = count.to_string().len();
+				return with_lock(config.lock_policy.unwrap_or_default(), || {
+					let mut previous = load_last_input().unwrap_or_default();
+					for i in 1..=count {
+						options.output_to = Some(out_dir.join(format!("wallpaper_{i:0digits$}")));
+						if plain.is_some() {
+							generate_wallpaper(None, &config, &options)?;
+							continue;
+						}
+						let background_path = pick_generate_background(&config, &previous, no_regen, force_regen && i == 1)?;
+						generate_wallpaper(Some(&background_path), &config, &options)?;
+						previous = background_path;
+					}
+					v_utils::log!("Wrote {count} wallpaper(s) to {}", out_dir.display());
+					Ok(())
+				});
+			}
 
-			// Generate wallpaper using the vision document
-			let result = generate_wallpaper(&vision_path, &config);
+			if plain.is_some() {
+				return with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(None, &config, &options));
+			}
 
-			// Remove lock
-			remove_lock()?;
+			let (background_path, result) = with_lock(config.lock_policy.unwrap_or_default(), || {
+				// Avoid repeating whatever is up right now, if it came from the same directory
+				let background_path = pick_generate_background(&config, &load_last_input().unwrap_or_default(), no_regen, force_regen)?;
+				let result = generate_wallpaper(Some(&background_path), &config, &options);
+				Ok((background_path, result))
+			})?;
 
-			// Save the vision path to cache (so extend without args also uses vision)
-			save_last_input(&vision_path)?;
+			// Save the background path to cache (so extend without args also uses it)
+			save_last_input(&background_path)?;
 
 			result
 		}
+		Command::Browse { directory, .. } => browse(&directory, &load_config()?, &options),
+		Command::Dim { amount } => dim(amount, &load_config()?),
+		Command::Stats => print_stats(),
+		Command::Doctor => doctor(load_config()),
+		Command::Clean => clean(),
+		Command::Schema => {
+			println!("{}", serde_json::to_string_pretty(&schemars::schema_for!(AppConfig))?);
+			Ok(())
+		}
+		Command::Benchmark { resolution, iterations } => benchmark(resolution, iterations, &load_config()?),
+		Command::DebugSafeArea { input, output, .. } => debug_safe_area(&input, &output, &load_config()?, &options),
+		Command::Daemon {
+			once, interval, random, directory, ..
+		} => run_daemon(once, std::time::Duration::from_secs(interval), random, directory, &load_config()?, &options),
+	}
+}
+
+/// Generates the wallpaper from `input_path` and sets it, then records it as the current input for `circle`/`refresh`.
+fn apply(input_path: &Path, config: &AppConfig, options: &GenerateOptions) -> Result<()> {
+	// Generate wallpaper (by default killing any previous background process still holding the lock)
+	let result = with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(Some(input_path), config, options));
+
+	// Save the input path to cache for next time
+	save_last_input(input_path)?;
+
+	result
+}
+
+fn run_daemon(once: bool, interval: std::time::Duration, random: bool, directory: Option<PathBuf>, config: &AppConfig, options: &GenerateOptions) -> Result<()> {
+	let listing = ListingOptions {
+		allow_single: true,
+		dedupe_by_hash: config.dedupe_by_hash.unwrap_or(false),
+		..Default::default()
+	};
+	loop {
+		let current_path = load_last_input()?;
+		let next_path = if random {
+			find_random_image(&current_path, directory.as_deref(), listing)?
+		} else {
+			find_next_image(&current_path, false, directory.as_deref(), listing)?
+		};
+		v_utils::log!("Rotating to: {}", next_path.display());
+
+		// Rotation is a one-shot child: it takes and releases the lock itself, and a concurrent Circle killing it won't take the daemon down.
+		// It is started with the config and flags we were, so every rotation renders the way the daemon was asked to.
+		let rotated = respawnable_exe().and_then(|current_exe| {
+			let mut rotation = ProcessCommand::new(current_exe);
+			rotation.args(top_level_args()).arg("extend").arg(&next_path).args(options.to_args());
+			Ok(rotation.status()?)
+		});
+		match rotated {
+			Ok(status) if !status.success() => warn!("Rotation to {} failed with {status}", next_path.display()),
+			Ok(_) => {}
+			Err(e) => {
+				v_utils::log!("Can't respawn ourselves for the rotation ({e}), generating it here instead");
+				if let Err(e) = apply(&next_path, config, options) {
+					warn!("Rotation to {} failed: {e}", next_path.display());
+				}
+			}
+		}
+
+		if once {
+			return Ok(());
+		}
+		std::thread::sleep(interval);
+	}
+}
+
+/// Sets `path` as the wallpaper on all outputs, through `set_command` if configured, swaymsg (or feh on X11) otherwise; or
+/// only on `output` if given, always through swaymsg.
+fn set_wallpaper(path: &Path, config: &AppConfig, output: Option<&str>) -> Result<()> {
+	let path_str = path.to_str().context("Wallpaper path is not valid UTF-8")?;
+	match output {
+		Some(output) => set_output_wallpaper(output, path)?,
+		None => {
+			let command: Vec<String> = match &config.set_command {
+				// Split on whitespace ourselves rather than going through a shell, so `*` stays literal and paths with spaces stay one argument
+				Some(template) => template.split_whitespace().map(|arg| arg.replace("{path}", path_str)).collect(),
+				None => match display_server() {
+					DisplayServer::Sway => ["swaymsg", "output", "*", "background", path_str, "fill"].map(String::from).to_vec(),
+					DisplayServer::X11 => ["feh", "--bg-fill", path_str].map(String::from).to_vec(),
+				},
+			};
+			run_set_command(&command)?;
+		}
+	}
+	if let Some(hook) = &config.post_set_hook {
+		run_post_set_hook(hook, path_str);
+	}
+	Ok(())
+}
+
+/// Starts `post_set_hook` without waiting on it. A watcher thread reports a failing exit, if we're still around by then.
+fn run_post_set_hook(hook: &str, path: &str) {
+	let command: Vec<String> = hook.split_whitespace().map(|arg| arg.replace("{path}", path)).collect();
+	let Some((program, args)) = command.split_first() else {
+		warn!("`post_set_hook` is empty");
+		return;
+	};
+	let child = ProcessCommand::new(program)
+		.args(args)
+		.stdin(std::process::Stdio::null())
+		.stdout(std::process::Stdio::null())
+		.stderr(std::process::Stdio::null())
+		.spawn();
+	match child {
+		Ok(mut child) => {
+			let hook = command.join(" ");
+			std::thread::spawn(move || match child.wait() {
+				Ok(status) if !status.success() => warn!("`{hook}` (post_set_hook) failed ({status})"),
+				Err(e) => warn!("Failed to wait on `{hook}` (post_set_hook): {e}"),
+				Ok(_) => {}
+			});
+		}
+		Err(e) => warn!("Failed to start `{}` (post_set_hook): {e}", command.join(" ")),
+	}
+}
+
+/// Sets the wallpaper of a single output; always through swaymsg, as `set_command` has no notion of outputs.
+fn set_output_wallpaper(output: &str, path: &Path) -> Result<()> {
+	if display_server() == DisplayServer::X11 {
+		bail!("Setting the wallpaper of a single output ({output}) isn't supported on X11");
+	}
+	let path_str = path.to_str().context("Wallpaper path is not valid UTF-8")?;
+	run_set_command(&["swaymsg", "output", output, "background", path_str, "fill"].map(String::from))
+}
+
+fn run_set_command(command: &[String]) -> Result<()> {
+	let (program, args) = command.split_first().context("`set_command` is empty")?;
+
+	// Right after a compositor restart or during output hotplug swaymsg can fail for a moment, so give it a couple more tries
+	const ATTEMPTS: u32 = 3;
+	for attempt in 1..=ATTEMPTS {
+		let output = ProcessCommand::new(program).args(args).output()?;
+		if output.status.success() {
+			return Ok(());
+		}
+		let stderr = String::from_utf8_lossy(&output.stderr);
+		if attempt == ATTEMPTS {
+			bail!("`{}` failed after {ATTEMPTS} attempts ({}): {}", command.join(" "), output.status, stderr.trim());
+		}
+		warn!("`{}` failed ({}), retrying: {}", command.join(" "), output.status, stderr.trim());
+		std::thread::sleep(std::time::Duration::from_millis(100 * 2u64.pow(attempt - 1)));
+	}
+	unreachable!()
+}
+
+/// Just the active output called `name`, listing the available ones if there's no such thing.
+fn only_output(outputs: Vec<SwayOutput>, name: &str) -> Result<Vec<SwayOutput>> {
+	let available = outputs.iter().filter(|o| o.current_mode.is_some()).map(|o| o.name.as_str()).collect::<Vec<_>>().join(", ");
+	let output = outputs.into_iter().find(|o| o.name == name && o.current_mode.is_some());
+	match output {
+		Some(output) => Ok(vec![output]),
+		None => bail!("No active output named {name:?}; active outputs: {available}"),
 	}
 }
 
-fn get_display_resolution() -> Result<(u32, u32)> {
+fn get_display_resolution(all_displays: &[(u32, u32)]) -> Result<(u32, u32)> {
 	// Find the smallest (most square) display to target
 	// This way on wider monitors we'll have unfilled space instead of cropping
-	let all_displays = get_all_active_displays()?;
 	if all_displays.is_empty() {
 		bail!("No active outputs found");
 	}
@@ -608,55 +2599,179 @@ fn get_display_resolution() -> Result<(u32, u32)> {
 	Ok((width, height))
 }
 
-fn get_all_active_displays() -> Result<Vec<(u32, u32)>> {
-	let output = ProcessCommand::new("swaymsg").args(["-t", "get_outputs"]).output()?;
-	let outputs: Vec<SwayOutput> = serde_json::from_slice(&output.stdout)?;
-	Ok(outputs.iter().filter_map(|o| o.current_mode.as_ref().map(|m| (m.width, m.height))).collect())
+fn get_all_active_displays(outputs_from: Option<&Path>, ignore: &[String]) -> Result<Vec<(u32, u32)>> {
+	Ok(without_ignored(get_outputs(outputs_from)?, ignore).iter().filter_map(SwayOutput::effective_resolution).collect())
 }
 
-fn calculate_safe_area(img_width: u32, img_height: u32, displays: &[(u32, u32)]) -> SafeArea {
-	// For each display, calculate how the image would be cropped when using "fill" mode
-	// "fill" scales the image to cover the entire screen, then crops the excess
+/// Drops the outputs named in `ignore`, noting each one that was there to drop.
+fn without_ignored(outputs: Vec<SwayOutput>, ignore: &[String]) -> Vec<SwayOutput> {
+	outputs
+		.into_iter()
+		.filter(|output| {
+			let ignored = ignore.contains(&output.name);
+			if ignored {
+				info!("Ignoring output {} (ignore_outputs)", output.name);
+			}
+			!ignored
+		})
+		.collect()
+}
+
+fn get_outputs(outputs_from: Option<&Path>) -> Result<Vec<SwayOutput>> {
+	let raw = match outputs_from {
+		Some(path) => std::fs::read(path).wrap_err_with(|| format!("Failed to read outputs from {}", path.display()))?,
+		None if display_server() == DisplayServer::X11 => {
+			let output = ProcessCommand::new("xrandr").arg("--query").output().wrap_err("Failed to run xrandr")?;
+			if !output.status.success() {
+				bail!("xrandr --query failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+			}
+			return Ok(parse_xrandr(&String::from_utf8_lossy(&output.stdout)));
+		}
+		None => {
+			let output = ProcessCommand::new("swaymsg").args(["-t", "get_outputs"]).output()?;
+			if !output.status.success() {
+				bail!("swaymsg -t get_outputs failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+			}
+			output.stdout
+		}
+	};
+	Ok(serde_json::from_slice(&raw)?)
+}
+
+/// What the outputs are asked of and the wallpaper is set through
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DisplayServer {
+	/// swaymsg
+	Sway,
+	/// xrandr and feh, e.g. for i3
+	X11,
+}
+
+/// Wayland (meaning sway) whenever `$WAYLAND_DISPLAY` is set, X11 if only `$DISPLAY` is
+fn display_server() -> DisplayServer {
+	let set = |var| std::env::var_os(var).is_some_and(|v| !v.is_empty());
+	match !set("WAYLAND_DISPLAY") && set("DISPLAY") {
+		true => DisplayServer::X11,
+		false => DisplayServer::Sway,
+	}
+}
+
+/// Outputs from `xrandr --query`, in the shape sway reports them. Connected ones are active if they have a geometry,
+/// which xrandr gives already rotated, so it's turned back into the mode.
+fn parse_xrandr(stdout: &str) -> Vec<SwayOutput> {
+	stdout
+		.lines()
+		.filter(|line| !line.starts_with(char::is_whitespace) && !line.starts_with("Screen "))
+		.filter_map(|line| {
+			let mut tokens = line.split_whitespace().filter(|&token| token != "primary");
+			let name = tokens.next()?.to_owned();
+			let connected = tokens.next()? == "connected";
+			let geometry = tokens.next().filter(|_| connected).and_then(|token| {
+				let (size, _position) = token.split_once('+')?;
+				let (width, height) = size.split_once('x')?;
+				Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?))
+			});
+			// Counter-clockwise `left` is sway's clockwise 270. Without a geometry, what follows is just the list of possible ones.
+			let transform = match geometry.and(tokens.next()) {
+				Some("left") => "270",
+				Some("right") => "90",
+				Some("inverted") => "180",
+				_ => "normal",
+			};
+			let current_mode = geometry.map(|(width, height)| match transform {
+				"90" | "270" => CurrentMode { width: height, height: width },
+				_ => CurrentMode { width, height },
+			});
+			Some(SwayOutput {
+				name,
+				current_mode,
+				rect: None,
+				scale: None,
+				transform: Some(transform.to_owned()),
+			})
+		})
+		.collect()
+}
 
+/// Displays the safe area has to fit on: those at least `min_width` wide (if set, and if that leaves any).
+///
+/// Mixing portrait and landscape outputs shrinks the intersection to a sliver, so that gets a warning.
+fn safe_area_displays(all_displays: &[(u32, u32)], min_width: Option<u32>) -> Vec<(u32, u32)> {
+	let mut displays: Vec<(u32, u32)> = all_displays.iter().copied().filter(|&(w, _)| min_width.is_none_or(|min| w >= min)).collect();
+	if displays.is_empty() {
+		warn!("All displays are narrower than safe_area_min_width, using all of them");
+		displays = all_displays.to_vec();
+	}
+	if displays.len() != all_displays.len() {
+		v_utils::elog!("Safe area accounts for {} of {} display(s)", displays.len(), all_displays.len());
+	}
+
+	let has_portrait = displays.iter().any(|&(w, h)| w < h);
+	let has_landscape = displays.iter().any(|&(w, h)| w > h);
+	if has_portrait && has_landscape {
+		warn!("Both portrait and landscape displays are active, the overlay may be cramped. Set `safe_area_min_width` to leave the portrait ones out.");
+	}
+	displays
+}
+
+/// Part of the image (in image coordinates) that stays visible on a display in "fill" mode.
+fn crop_window(img_width: u32, img_height: u32, (display_width, display_height): (u32, u32)) -> SafeArea {
+	// "fill" scales the image to cover the entire screen, then crops the excess
 	let img_ratio = img_width as f32 / img_height as f32;
+	let display_ratio = display_width as f32 / display_height as f32;
+
+	// Calculate how the image would be scaled and cropped for this display
+	let (scaled_width, _scaled_height, x_offset, y_offset) = if img_ratio > display_ratio {
+		// Image is wider than display - will crop horizontally
+		let scaled_height = display_height;
+		let scaled_width = (display_height as f32 * img_ratio) as u32;
+		let x_offset = scaled_width.saturating_sub(display_width) / 2;
+		(scaled_width, scaled_height, x_offset, 0)
+	} else {
+		// Image is taller than display - will crop vertically
+		let scaled_width = display_width;
+		let scaled_height = (display_width as f32 / img_ratio) as u32;
+		let y_offset = scaled_height.saturating_sub(display_height) / 2;
+		(scaled_width, scaled_height, 0, y_offset)
+	};
+
+	// Convert the cropped area back to original image coordinates
+	let scale_factor = img_width as f32 / scaled_width as f32;
+	let crop_x_start = (x_offset as f32 * scale_factor) as u32;
+	let crop_y_start = (y_offset as f32 * scale_factor) as u32;
+	// Float rounding can push the crop window a pixel or so past the image edge
+	let crop_x_end = (crop_x_start + (display_width as f32 * scale_factor) as u32).min(img_width);
+	let crop_y_end = (crop_y_start + (display_height as f32 * scale_factor) as u32).min(img_height);
+
+	SafeArea {
+		x: crop_x_start,
+		y: crop_y_start,
+		width: crop_x_end.saturating_sub(crop_x_start),
+		height: crop_y_end.saturating_sub(crop_y_start),
+	}
+}
 
+fn calculate_safe_area(img_width: u32, img_height: u32, displays: &[(u32, u32)]) -> SafeArea {
+	// For each display, calculate how the image would be cropped when using "fill" mode
 	let mut min_x = 0;
 	let mut min_y = 0;
 	let mut max_x = img_width;
 	let mut max_y = img_height;
 
-	for &(display_width, display_height) in displays {
-		let display_ratio = display_width as f32 / display_height as f32;
-
-		// Calculate how the image would be scaled and cropped for this display
-		let (scaled_width, _scaled_height, x_offset, y_offset) = if img_ratio > display_ratio {
-			// Image is wider than display - will crop horizontally
-			let scaled_height = display_height;
-			let scaled_width = (display_height as f32 * img_ratio) as u32;
-			let x_offset = (scaled_width - display_width) / 2;
-			(scaled_width, scaled_height, x_offset, 0)
-		} else {
-			// Image is taller than display - will crop vertically
-			let scaled_width = display_width;
-			let scaled_height = (display_width as f32 / img_ratio) as u32;
-			let y_offset = (scaled_height - display_height) / 2;
-			(scaled_width, scaled_height, 0, y_offset)
-		};
-
-		// Convert the cropped area back to original image coordinates
-		let scale_factor = img_width as f32 / scaled_width as f32;
-		let crop_x_start = (x_offset as f32 * scale_factor) as u32;
-		let crop_y_start = (y_offset as f32 * scale_factor) as u32;
-		let crop_x_end = crop_x_start + (display_width as f32 * scale_factor) as u32;
-		let crop_y_end = crop_y_start + (display_height as f32 * scale_factor) as u32;
+	for &display in displays {
+		let crop = crop_window(img_width, img_height, display);
 
 		// Update the safe area to be the intersection of all cropped areas
-		min_x = min_x.max(crop_x_start);
-		min_y = min_y.max(crop_y_start);
-		max_x = max_x.min(crop_x_end);
-		max_y = max_y.min(crop_y_end);
+		min_x = min_x.max(crop.x);
+		min_y = min_y.max(crop.y);
+		max_x = max_x.min(crop.x + crop.width);
+		max_y = max_y.min(crop.y + crop.height);
 	}
 
+	// Crop windows that don't overlap leave `min > max`; collapse to an empty area that still starts inside the image
+	let min_x = min_x.min(max_x);
+	let min_y = min_y.min(max_y);
+
 	SafeArea {
 		x: min_x,
 		y: min_y,
@@ -665,7 +2780,209 @@ fn calculate_safe_area(img_width: u32, img_height: u32, displays: &[(u32, u32)])
 	}
 }
 
-fn resize_fill(img: image::DynamicImage, target_width: u32, target_height: u32) -> image::DynamicImage {
+/// Decodes an image along with its embedded ICC profile, if any. `frame` picks a specific frame of an animated input.
+fn open_with_icc(path: &Path, frame: Option<usize>) -> Result<(image::DynamicImage, Option<Vec<u8>>)> {
+	use image::ImageDecoder as _;
+
+	let reader = image::ImageReader::open(path)?.with_guessed_format()?;
+	if let Some(frame) = frame {
+		return decode_frame(path, reader.format(), frame);
+	}
+	let mut decoder = reader.into_decoder()?;
+	let icc_profile = decoder.icc_profile()?;
+	Ok((image::DynamicImage::from_decoder(decoder)?, icc_profile))
+}
+
+fn decode_frame(path: &Path, format: Option<image::ImageFormat>, n: usize) -> Result<(image::DynamicImage, Option<Vec<u8>>)> {
+	use image::{
+		AnimationDecoder as _, ImageDecoder as _, ImageFormat,
+		codecs::{gif::GifDecoder, webp::WebPDecoder},
+	};
+
+	let file = std::io::BufReader::new(std::fs::File::open(path)?);
+	let (icc_profile, mut frames) = match format {
+		Some(ImageFormat::Gif) => {
+			let mut decoder = GifDecoder::new(file)?;
+			(decoder.icc_profile()?, decoder.into_frames())
+		}
+		Some(ImageFormat::WebP) => {
+			let mut decoder = WebPDecoder::new(file)?;
+			(decoder.icc_profile()?, decoder.into_frames())
+		}
+		_ => bail!("--frame only applies to animated gif/webp inputs, got: {}", path.display()),
+	};
+	let frame = frames.nth(n).with_context(|| format!("{} has no frame {n}", path.display()))??;
+	Ok((image::DynamicImage::ImageRgba8(frame.into_buffer()), icc_profile))
+}
+
+/// `jpeg_quality` (1-100) only matters for [`OutputFormat::Jpeg`], `webp_encoding` only for [`OutputFormat::Webp`].
+fn save_image_with_icc(
+	img: &image::RgbaImage,
+	writer: impl Write,
+	icc_profile: Option<&[u8]>,
+	format: OutputFormat,
+	jpeg_quality: u8,
+	webp_encoding: WebpEncoding,
+	dpi: Option<u32>,
+) -> Result<()> {
+	use image::{
+		ImageEncoder as _,
+		codecs::{
+			jpeg::{JpegEncoder, PixelDensity, PixelDensityUnit},
+			png::PngEncoder,
+			webp::WebPEncoder,
+		},
+	};
+
+	let mut writer = std::io::BufWriter::new(writer);
+	match format {
+		OutputFormat::Png => {
+			// The png encoder has no say over `pHYs`, so encode to memory and splice the chunk in
+			let mut encoded = Vec::new();
+			let mut encoder = PngEncoder::new(&mut encoded);
+			if let Some(icc_profile) = icc_profile {
+				encoder.set_icc_profile(icc_profile.to_vec())?;
+			}
+			encoder.write_image(img.as_raw(), img.width(), img.height(), image::ExtendedColorType::Rgba8)?;
+			if let Some(dpi) = dpi {
+				insert_png_phys(&mut encoded, dpi);
+			}
+			writer.write_all(&encoded)?;
+			writer.flush()?;
+		}
+		OutputFormat::Jpeg => {
+			// No alpha in jpeg
+			let rgb = image::DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+			let mut encoder = JpegEncoder::new_with_quality(writer, jpeg_quality.clamp(1, 100));
+			if let Some(icc_profile) = icc_profile {
+				encoder.set_icc_profile(icc_profile.to_vec())?;
+			}
+			if let Some(dpi) = dpi {
+				let dpi = dpi.min(u16::MAX as u32) as u16;
+				encoder.set_pixel_density(PixelDensity {
+					density: (dpi, dpi),
+					unit: PixelDensityUnit::Inches,
+				});
+			}
+			encoder.write_image(rgb.as_raw(), rgb.width(), rgb.height(), image::ExtendedColorType::Rgb8)?;
+		}
+		OutputFormat::Webp => match webp_encoding {
+			WebpEncoding::Lossless => {
+				let mut encoder = WebPEncoder::new_lossless(writer);
+				if let Some(icc_profile) = icc_profile {
+					encoder.set_icc_profile(icc_profile.to_vec())?;
+				}
+				encoder.write_image(img.as_raw(), img.width(), img.height(), image::ExtendedColorType::Rgba8)?;
+			}
+			// `image` only encodes lossless webp; libwebp does both, but without an ICC profile
+			#[cfg(feature = "lossy-webp")]
+			WebpEncoding::Lossy(quality) => {
+				if icc_profile.is_some() {
+					warn!("Lossy webp output can't carry the source's ICC profile, dropping it");
+				}
+				let encoded = webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height()).encode(quality.clamp(0.0, 100.0));
+				writer.write_all(&encoded)?;
+				writer.flush()?;
+			}
+		},
+	}
+	Ok(())
+}
+
+/// How [`OutputFormat::Webp`] output is encoded
+#[derive(Clone, Copy, Debug)]
+enum WebpEncoding {
+	Lossless,
+	/// Quality, 0-100
+	#[cfg(feature = "lossy-webp")]
+	Lossy(f32),
+}
+
+impl WebpEncoding {
+	fn from_config(config: &AppConfig) -> Self {
+		match config.webp_lossless.unwrap_or(true) {
+			true => WebpEncoding::Lossless,
+			#[cfg(feature = "lossy-webp")]
+			false => WebpEncoding::Lossy(config.webp_quality.unwrap_or(90.0)),
+			#[cfg(not(feature = "lossy-webp"))]
+			false => {
+				warn!("webp_lossless = false needs the `lossy-webp` feature, encoding lossless");
+				WebpEncoding::Lossless
+			}
+		}
+	}
+}
+
+/// Adds a `pHYs` chunk right after `IHDR`, which is where the spec wants it (before any `IDAT`).
+fn insert_png_phys(png: &mut Vec<u8>, dpi: u32) {
+	// 8 byte signature, then IHDR: length, type, 13 bytes of data, CRC
+	const AFTER_IHDR: usize = 8 + 4 + 4 + 13 + 4;
+	// pHYs only knows meters
+	let pixels_per_meter = (dpi as f64 / 0.0254).round() as u32;
+
+	let mut chunk_data = Vec::with_capacity(4 + 9);
+	chunk_data.extend_from_slice(b"pHYs");
+	chunk_data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+	chunk_data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+	chunk_data.push(1); // unit: meter
+
+	let mut chunk = Vec::with_capacity(4 + chunk_data.len() + 4);
+	chunk.extend_from_slice(&9u32.to_be_bytes());
+	chunk.extend_from_slice(&chunk_data);
+	chunk.extend_from_slice(&crc32(&chunk_data).to_be_bytes());
+	png.splice(AFTER_IHDR..AFTER_IHDR, chunk);
+}
+
+/// CRC-32 (IEEE), as PNG chunks are checksummed with
+fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = u32::MAX;
+	for &byte in bytes {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+fn apply_image_filter(img: image::DynamicImage, filter: ImageFilter) -> image::DynamicImage {
+	use image::DynamicImage;
+
+	match filter {
+		ImageFilter::Grayscale => DynamicImage::ImageRgba8(img.grayscale().to_rgba8()),
+		ImageFilter::Sepia => {
+			let mut rgba = img.to_rgba8();
+			for pixel in rgba.pixels_mut() {
+				let [r, g, b, _] = pixel.0.map(|c| c as f32);
+				pixel[0] = (0.393 * r + 0.769 * g + 0.189 * b).min(255.0) as u8;
+				pixel[1] = (0.349 * r + 0.686 * g + 0.168 * b).min(255.0) as u8;
+				pixel[2] = (0.272 * r + 0.534 * g + 0.131 * b).min(255.0) as u8;
+			}
+			DynamicImage::ImageRgba8(rgba)
+		}
+		ImageFilter::Invert => {
+			let mut img = img;
+			img.invert();
+			img
+		}
+		// Relative to the size, so the look doesn't depend on the resolution
+		ImageFilter::Blur => {
+			let sigma = img.width().min(img.height()) as f32 / 200.0;
+			img.blur(sigma)
+		}
+	}
+}
+
+fn resize_fill(img: image::DynamicImage, target_width: u32, target_height: u32, filter: image::imageops::FilterType) -> image::DynamicImage {
+	fill_with(img, target_width, target_height, |img, width, height| img.resize_exact(width, height, filter))
+}
+
+/// [`resize_fill`] on `image`'s `thumbnail` scaling: far faster at large reductions, but softer. For previews only.
+fn thumbnail_fill(img: image::DynamicImage, target_width: u32, target_height: u32) -> image::DynamicImage {
+	fill_with(img, target_width, target_height, |img, width, height| img.thumbnail_exact(width, height))
+}
+
+fn fill_with(img: image::DynamicImage, target_width: u32, target_height: u32, resize: impl FnOnce(&image::DynamicImage, u32, u32) -> image::DynamicImage) -> image::DynamicImage {
 	use image::{DynamicImage, GenericImageView, imageops};
 
 	let (img_width, img_height) = img.dimensions();
@@ -682,7 +2999,7 @@ fn resize_fill(img: image::DynamicImage, target_width: u32, target_height: u32)
 		(scaled_width, scaled_height)
 	};
 
-	let resized = img.resize_exact(scaled_width, scaled_height, imageops::FilterType::Lanczos3);
+	let resized = resize(&img, scaled_width, scaled_height);
 
 	// Crop from right/bottom (keep left/top aligned) since content typically starts there
 	let x_offset = 0;
@@ -691,32 +3008,147 @@ fn resize_fill(img: image::DynamicImage, target_width: u32, target_height: u32)
 	DynamicImage::ImageRgba8(imageops::crop_imm(&resized.to_rgba8(), x_offset, y_offset, target_width, target_height).to_image())
 }
 
-fn generate_text_svg(text: &str, author: Option<&str>, balance: Option<&str>, width: u32, height: u32, safe_area: &SafeArea, text_padding: u32) -> Result<String> {
-	// Nested padding levels: [level0, level1, level2, level3, level4]
-	// Each level is half of the previous
-	let padding_levels: [u32; 5] = [text_padding, text_padding / 2, text_padding / 4, text_padding / 8, text_padding / 16];
-	// Escape HTML entities in text
-	let escaped_text = text
-		.replace('&', "&amp;")
+/// Expands tabs and drops other control characters, which `<text>` renders unpredictably. Works on a single line.
+fn sanitize_line(line: &str, tab_width: usize) -> String {
+	line.chars()
+		.flat_map(|c| match c {
+			'\t' => std::iter::repeat_n(' ', tab_width),
+			c if c.is_control() => std::iter::repeat_n(' ', 0),
+			c => std::iter::repeat_n(c, 1),
+		})
+		.collect()
+}
+
+/// Breaks `line` at spaces so no piece exceeds `max_chars`; words longer than that on their own are split mid-word.
+fn wrap_line(line: &str, max_chars: usize) -> Vec<String> {
+	if max_chars == 0 || line.chars().count() <= max_chars {
+		return vec![line.to_owned()];
+	}
+	let mut lines = Vec::new();
+	let mut current = String::new();
+	for word in line.split(' ') {
+		let mut word: Vec<char> = word.chars().collect();
+		let current_len = current.chars().count();
+		if current_len > 0 && current_len + 1 + word.len() <= max_chars {
+			current.push(' ');
+			current.extend(&word);
+			continue;
+		}
+		if current_len > 0 {
+			lines.push(std::mem::take(&mut current));
+		}
+		while word.len() > max_chars {
+			lines.push(word.drain(..max_chars).collect());
+		}
+		current.extend(word);
+	}
+	lines.push(current);
+	lines
+}
+
+fn escape_xml(text: &str) -> String {
+	text.replace('&', "&amp;")
 		.replace('<', "&lt;")
 		.replace('>', "&gt;")
 		.replace('"', "&quot;")
-		.replace('\'', "&apos;");
+		.replace('\'', "&apos;")
+}
 
-	// Calculate text widths (approximate for monospace: char_count * char_width)
-	let quote_font_size = 28;
-	let char_width_quote = (quote_font_size as f32 * 0.6) as u32; // Monospace chars are ~0.6 of font size
-	let quote_lines: Vec<&str> = escaped_text.lines().collect();
-	let max_quote_line_len = quote_lines.iter().map(|l| l.len()).max().unwrap_or(0);
-	let quote_text_width = max_quote_line_len as u32 * char_width_quote;
+/// Wraps `lines` to `max_chars`, then cuts them down to `max_lines`, marking the cut with an ellipsis that still fits.
+fn truncate_lines(lines: &mut Vec<String>, max_lines: usize, max_chars: usize) {
+	*lines = lines.iter().flat_map(|line| wrap_line(line, max_chars)).collect();
+	if lines.len() <= max_lines {
+		return;
+	}
+	lines.truncate(max_lines);
+	if let Some(last) = lines.last_mut() {
+		let keep = max_chars.saturating_sub(1).min(last.chars().count());
+		*last = format!("{}…", last.chars().take(keep).collect::<String>().trim_end());
+	}
+}
 
-	// Position quote in top-right corner of safe area with level 0 padding
-	// We use right alignment, so quote_right_edge is the anchor point
-	let quote_right_edge = safe_area.x + safe_area.width - padding_levels[0];
-	let quote_x = quote_right_edge - quote_text_width;
-	let quote_y = safe_area.y + padding_levels[0] * 2;
+/// Lowest font scale `max_overlay_fraction` may shrink to; past this the text gets unreadable anyway
+const MIN_OVERLAY_SCALE: f32 = 0.4;
 
-	// Create tspan elements
+fn generate_text_svg(params: &CompositeParams) -> Result<(String, TextLayout)> {
+	let (mut svg, mut layout, mut block_height) = text_svg_at_scale(params, 1.0);
+	let Some(fraction) = params.max_overlay_fraction else {
+		return Ok((svg, layout));
+	};
+	let max_height = (params.safe_area.height as f32 * fraction.clamp(0.0, 1.0)) as u32;
+	// Paddings don't shrink with the fonts, so height isn't proportional to scale; close in over a few rounds
+	let mut scale = 1.0;
+	for _ in 0..8 {
+		if block_height <= max_height || scale <= MIN_OVERLAY_SCALE {
+			break;
+		}
+		scale = (scale * max_height as f32 / block_height as f32).max(MIN_OVERLAY_SCALE);
+		(svg, layout, block_height) = text_svg_at_scale(params, scale);
+	}
+	if block_height > max_height {
+		warn!(
+			"Text block is {block_height}px tall even at {:.0}% font size, over the {max_height}px max_overlay_fraction allows",
+			scale * 100.0
+		);
+	} else if scale < 1.0 {
+		info!("Shrunk fonts to {:.0}% to fit max_overlay_fraction", scale * 100.0);
+	}
+	Ok((svg, layout))
+}
+
+/// The overlay SVG with every font size multiplied by `scale`, plus the main block's height
+fn text_svg_at_scale(params: &CompositeParams, scale: f32) -> (String, TextLayout, u32) {
+	let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+	let CompositeParams {
+		text,
+		author,
+		source,
+		balances,
+		width,
+		height,
+		safe_area,
+		text_padding,
+		line_spacing,
+		tab_width,
+		text_color,
+		scrim_color,
+		quote_color,
+		opacity,
+		..
+	} = *params;
+	// Nested padding levels: [level0, level1, level2, level3, level4]
+	// Each level is half of the previous
+	let padding_levels: [u32; 5] = [text_padding, text_padding / 2, text_padding / 4, text_padding / 8, text_padding / 16];
+
+	// Calculate text widths (approximate for monospace: char_count * char_width)
+	let quote_font_size = scaled(28);
+	let char_width_quote = ((quote_font_size as f32 * 0.6) as u32).max(1); // Monospace chars are ~0.6 of font size
+	let mut raw_quote_lines: Vec<String> = text.map(|t| t.lines().map(|l| sanitize_line(l, tab_width)).collect()).unwrap_or_default();
+	if let Some(max_lines) = params.quote_max_lines {
+		let max_chars = (safe_area.width.saturating_sub(padding_levels[0] * 2) / char_width_quote) as usize;
+		// Leave room for the marks, which go on after truncating
+		let max_chars = max_chars.saturating_sub(params.quote_marks.map_or(0, |_| 1));
+		truncate_lines(&mut raw_quote_lines, max_lines, max_chars);
+	}
+	if let Some((open, close)) = params.quote_marks
+		&& !raw_quote_lines.is_empty()
+	{
+		let last = raw_quote_lines.len() - 1;
+		raw_quote_lines[0].insert(0, open);
+		raw_quote_lines[last].push(close);
+	}
+	let max_quote_line_len = raw_quote_lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+	let quote_text_width = max_quote_line_len as i64 * char_width_quote as i64;
+	// Escape HTML entities in text
+	let quote_lines: Vec<String> = raw_quote_lines.iter().map(|l| escape_xml(l)).collect();
+
+	// Position quote in top-right corner of safe area with level 0 padding
+	// We use right alignment, so quote_right_edge is the anchor point
+	let quote_right_edge = i64::from(safe_area.x + safe_area.width) - i64::from(padding_levels[0]);
+	let quote_x = quote_right_edge - quote_text_width;
+	let quote_y = safe_area.y + padding_levels[0] * 2;
+
+	// Create tspan elements
 	let quote_tspans: String = quote_lines
 		.iter()
 		.enumerate()
@@ -724,88 +3156,195 @@ fn generate_text_svg(text: &str, author: Option<&str>, balance: Option<&str>, wi
 			if i == 0 {
 				format!(r#"<tspan x="{quote_x}" dy="0">{line}</tspan>"#)
 			} else {
-				format!(r#"<tspan x="{quote_x}" dy="1.2em">{line}</tspan>"#)
+				format!(r#"<tspan x="{quote_x}" dy="{line_spacing}em">{line}</tspan>"#)
 			}
 		})
 		.collect::<Vec<_>>()
 		.join("\n      ");
 
 	// Calculate height of quote block
-	let line_height = 34; // 28px * 1.2 ≈ 34
+	let line_height = (quote_font_size as f32 * line_spacing).round() as u32; // 28px * 1.2 ≈ 34
+	let author_font_size = scaled(21);
 	let quote_height = quote_lines.len() as u32 * line_height;
 
-	// Author is nested inside quote component (level 1 padding)
-	let author_y = quote_y + quote_height + padding_levels[1];
+	// Author is nested inside quote component (level 1 padding), or takes the quote's place if there's no quote text
+	let author_y = if quote_lines.is_empty() { quote_y } else { quote_y + quote_height + padding_levels[1] };
+
+	// Leftmost point of any text, for sizing the scrim
+	let mut block_left = quote_x;
+	let mut layout = TextLayout {
+		quote: (quote_x, quote_y.into()),
+		..Default::default()
+	};
 
 	let (author_element, author_height) = if let Some(author) = author {
-		let escaped_author = author
-			.replace('&', "&amp;")
-			.replace('<', "&lt;")
-			.replace('>', "&gt;")
-			.replace('"', "&quot;")
-			.replace('\'', "&apos;");
+		let author = sanitize_line(author, tab_width);
+		let escaped_author = escape_xml(&author);
 
 		// Calculate author text width
 		let author_text = format!("© {escaped_author}");
+		let author_text_width = (author.chars().count() as i64 + 2) * (author_font_size as f32 * 0.6) as i64;
+		block_left = block_left.min(quote_right_edge - author_text_width);
 
 		// Position author at the same right edge as the quote (right-aligned with text-anchor: end)
 		let author_x = quote_right_edge;
-		let author_height = 21;
+		layout.author = Some((author_x, author_y.into()));
+		let author_height = author_font_size;
 		(format!(r#"<text class="author" x="{author_x}" y="{author_y}">{author_text}</text>"#), author_height)
 	} else {
 		(String::new(), 0)
 	};
 
+	// Source is a subtle line under the author, still part of the quote component (level 2 padding)
+	let source_font_size = scaled(16);
+	let source_y = if author.is_some() { author_y + author_height + padding_levels[2] } else { author_y };
+	let source_element = if let Some(source) = source {
+		let source = sanitize_line(source, tab_width);
+		let source_text_width = source.chars().count() as i64 * (source_font_size as f32 * 0.6) as i64;
+		block_left = block_left.min(quote_right_edge - source_text_width);
+		layout.source = Some((quote_right_edge, source_y.into()));
+		format!(r#"<text class="source" x="{quote_right_edge}" y="{source_y}">{}</text>"#, escape_xml(&source))
+	} else {
+		String::new()
+	};
+
 	// Calculate the bottom of the quote component (for positioning balance below)
 	// Use level 0 padding after the entire quote component
-	let quote_bottom_y = if author.is_some() {
+	let quote_bottom_y = if source.is_some() {
+		source_y + source_font_size + padding_levels[0]
+	} else if author.is_some() {
 		author_y + author_height + padding_levels[0]
 	} else {
 		quote_y + quote_height + padding_levels[0]
 	};
 
-	let balance_element = if let Some(balance) = balance {
-		let escaped_balance = balance
-			.replace('&', "&amp;")
-			.replace('<', "&lt;")
-			.replace('>', "&gt;")
-			.replace('"', "&quot;")
-			.replace('\'', "&apos;");
+	// Each balance gets its own block, stacked right below the quote component (level 0 padding between blocks)
+	let mut balance_y = quote_bottom_y;
+	let balance_element = balances
+		.iter()
+		.map(|block| {
+			// Calculate balance text width
+			let font_size = scaled(block.font_size);
+			let char_width_balance = (font_size as f32 * 0.6) as u32;
+			// Command output's own newlines are hard breaks; anything wider than the safe area wraps on top of that
+			let max_chars = (safe_area.width.saturating_sub(padding_levels[0] * 2) / char_width_balance.max(1)) as usize;
+			let raw_balance_lines: Vec<String> = block.text.lines().flat_map(|l| wrap_line(&sanitize_line(l, tab_width), max_chars)).collect();
+			let max_balance_line_len = raw_balance_lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+			let balance_text_width = max_balance_line_len as i64 * char_width_balance as i64;
+			let balance_lines: Vec<String> = raw_balance_lines.iter().map(|l| escape_xml(l)).collect();
+
+			// Right-aligned with the quote (level 0 padding from right edge)
+			let balance_x = quote_right_edge - balance_text_width;
+			block_left = block_left.min(balance_x);
+			layout.balances.push((balance_x, balance_y.into()));
+
+			// Create tspan elements
+			let balance_tspans: String = balance_lines
+				.iter()
+				.enumerate()
+				.map(|(i, line)| {
+					if i == 0 {
+						format!(r#"<tspan x="{balance_x}" dy="0">{line}</tspan>"#)
+					} else {
+						format!(r#"<tspan x="{balance_x}" dy="{line_spacing}em">{line}</tspan>"#)
+					}
+				})
+				.collect::<Vec<_>>()
+				.join("\n      ");
 
-		// Calculate balance text width
-		let balance_font_size = 20;
-		let char_width_balance = (balance_font_size as f32 * 0.6) as u32;
-		let balance_lines: Vec<&str> = escaped_balance.lines().collect();
-		let max_balance_line_len = balance_lines.iter().map(|l| l.len()).max().unwrap_or(0);
-		let balance_text_width = max_balance_line_len as u32 * char_width_balance;
+			let mut style = format!("font-size: {font_size}px;");
+			if let Some(color) = &block.color {
+				style.push_str(&format!(" fill: {};", escape_xml(color)));
+			}
 
-		// Position balance right below the quote component (level 0 padding from right edge)
-		let balance_x = safe_area.x + safe_area.width - padding_levels[0] - balance_text_width;
-		let balance_y = quote_bottom_y;
+			let element = format!(
+				r#"<text class="balance" x="{balance_x}" y="{balance_y}" style="{style}">
+      {balance_tspans}
+  </text>"#
+			);
+			balance_y += (balance_lines.len() as f32 * font_size as f32 * line_spacing) as u32 + padding_levels[0];
+			element
+		})
+		.collect::<Vec<_>>()
+		.join("\n  ");
+
+	// Timestamp closes off the right-hand column, right-aligned like the author
+	let timestamp_font_size = scaled(14);
+	let (timestamp_element, column_bottom) = match params.timestamp {
+		Some(timestamp) => {
+			let timestamp = sanitize_line(timestamp, tab_width);
+			let timestamp_y = balance_y.max(quote_bottom_y);
+			block_left = block_left.min(quote_right_edge - timestamp.chars().count() as i64 * (timestamp_font_size as f32 * 0.6) as i64);
+			layout.timestamp = Some((quote_right_edge, timestamp_y.into()));
+			(
+				format!(r#"<text class="timestamp" x="{quote_right_edge}" y="{timestamp_y}">{}</text>"#, escape_xml(&timestamp)),
+				timestamp_y + timestamp_font_size + padding_levels[0],
+			)
+		}
+		None => (String::new(), quote_bottom_y.max(balance_y)),
+	};
 
-		// Create tspan elements
-		let balance_tspans: String = balance_lines
-			.iter()
-			.enumerate()
-			.map(|(i, line)| {
-				if i == 0 {
-					format!(r#"<tspan x="{balance_x}" dy="0">{line}</tspan>"#)
-				} else {
-					format!(r#"<tspan x="{balance_x}" dy="1.2em">{line}</tspan>"#)
-				}
-			})
-			.collect::<Vec<_>>()
-			.join("\n      ");
+	// Filename caption goes under everything else, set like the source
+	let caption_font_size = scaled(14);
+	let (caption_element, column_bottom) = match params.caption {
+		Some(caption) => {
+			let caption = sanitize_line(caption, tab_width);
+			let caption_y = column_bottom;
+			block_left = block_left.min(quote_right_edge - caption.chars().count() as i64 * (caption_font_size as f32 * 0.6) as i64);
+			layout.caption = Some((quote_right_edge, caption_y.into()));
+			(
+				format!(r#"<text class="caption" x="{quote_right_edge}" y="{caption_y}">{}</text>"#, escape_xml(&caption)),
+				caption_y + caption_font_size + padding_levels[0],
+			)
+		}
+		None => (String::new(), column_bottom),
+	};
 
+	let block_height = column_bottom.saturating_sub(quote_y.saturating_sub(quote_font_size));
+	let scrim_element = if let Some(scrim_color) = scrim_color {
+		let scrim_x = block_left - i64::from(padding_levels[1]);
+		let scrim_y = quote_y.saturating_sub(quote_font_size + padding_levels[1]);
+		let scrim_width = quote_right_edge + i64::from(padding_levels[1]) - scrim_x;
+		// Both bottoms carry a trailing level 0 padding, trade it for level 1
+		let scrim_height = (column_bottom - padding_levels[0] + padding_levels[1]).saturating_sub(scrim_y);
 		format!(
-			r#"<text class="balance" x="{balance_x}" y="{balance_y}">
-      {balance_tspans}
-  </text>"#
+			r#"<rect x="{scrim_x}" y="{scrim_y}" width="{scrim_width}" height="{scrim_height}" rx="{}" fill="{}"/>"#,
+			padding_levels[2],
+			escape_xml(scrim_color)
 		)
 	} else {
 		String::new()
 	};
 
+	let (block_open, block_close) = match params.block_cell {
+		Some(cell) => {
+			let block_top = quote_y.saturating_sub(quote_font_size + padding_levels[1]);
+			let (dx, dy) = thirds_offset((block_left, block_top.into(), quote_right_edge, column_bottom.into()), cell, safe_area, padding_levels[0]);
+			let shift = |(x, y): (i64, i64)| (x + dx, y + dy);
+			layout.quote = shift(layout.quote);
+			layout.author = layout.author.map(shift);
+			layout.source = layout.source.map(shift);
+			layout.balances.iter_mut().for_each(|anchor| *anchor = shift(*anchor));
+			layout.timestamp = layout.timestamp.map(shift);
+			layout.caption = layout.caption.map(shift);
+			(format!(r#"<g transform="translate({dx} {dy})">"#), "</g>")
+		}
+		None => (String::new(), ""),
+	};
+	let extra_quote_elements = params
+		.extra_quotes
+		.iter()
+		.zip(EXTRA_QUOTE_CORNERS)
+		.map(|(block, corner)| {
+			let (element, anchor) = corner_quote_svg(block, corner, params, padding_levels, (quote_font_size, author_font_size));
+			layout.extra_quotes.push(anchor);
+			element
+		})
+		.collect::<Vec<_>>()
+		.join("\n  ");
+	let text_color = escape_xml(text_color);
+	let quote_style = quote_color.map(|color| format!(r#" style="fill: {};""#, escape_xml(color))).unwrap_or_default();
+
 	let svg = format!(
 		r#"<?xml version="1.0" encoding="UTF-8"?>
 <svg width="{width}" height="{height}" xmlns="http://www.w3.org/2000/svg">
@@ -813,42 +3352,184 @@ fn generate_text_svg(text: &str, author: Option<&str>, balance: Option<&str>, wi
     <style>
       .quote {{
         font-family: 'DejaVu Sans Mono';
-        font-size: 28px;
-        fill: white;
+        font-size: {quote_font_size}px;
+        fill: {text_color};
+        fill-opacity: {quote_opacity};
         text-anchor: start;
       }}
       .author {{
         font-family: 'DejaVu Sans Mono';
-        font-size: 21px;
-        fill: white;
+        font-size: {author_font_size}px;
+        fill: {text_color};
+        fill-opacity: {author_opacity};
+        text-anchor: end;
+      }}
+      .source {{
+        font-family: 'DejaVu Sans Mono';
+        font-size: {source_font_size}px;
+        font-style: italic;
+        fill: {text_color};
+        fill-opacity: {source_opacity};
         text-anchor: end;
       }}
       .balance {{
         font-family: 'DejaVu Sans Mono';
-        font-size: 20px;
-        fill: white;
+        fill: {text_color};
+        fill-opacity: {balance_opacity};
         text-anchor: start;
       }}
+      .timestamp {{
+        font-family: 'DejaVu Sans Mono';
+        font-size: {timestamp_font_size}px;
+        fill: {text_color};
+        fill-opacity: {source_opacity};
+        text-anchor: end;
+      }}
+      .caption {{
+        font-family: 'DejaVu Sans Mono';
+        font-size: {caption_font_size}px;
+        font-style: italic;
+        fill: {text_color};
+        fill-opacity: {source_opacity};
+        text-anchor: end;
+      }}
     </style>
   </defs>
-  <text class="quote" x="{quote_x}" y="{quote_y}">
+  {block_open}
+  {scrim_element}
+  <text class="quote" x="{quote_x}" y="{quote_y}"{quote_style}>
       {quote_tspans}
   </text>
   {author_element}
+  {source_element}
   {balance_element}
+  {timestamp_element}
+  {caption_element}
+  {block_close}
+  {extra_quote_elements}
 </svg>"#,
+		quote_opacity = opacity.quote,
+		author_opacity = opacity.author,
+		source_opacity = opacity.author * 0.6,
+		balance_opacity = opacity.balance,
 	);
 
-	Ok(svg)
+	(svg, layout, block_height)
 }
 
-fn composite_text_on_image(params: &CompositeParams) -> Result<()> {
-	// Load background image
-	let mut bg_image = image::open(params.bg_image_path)?.to_rgba8();
+/// An extra quote (and its author) laid out in `corner` of the safe area, at the main block's (already scaled) font sizes
+/// and mirroring its spacing, quote marks and `quote_max_lines`. Lines wrap at half the safe area's width, so the two
+/// bottom corners never run into each other. Returns the elements and the first baseline's left edge.
+fn corner_quote_svg(block: &QuoteBlock, corner: Corner, params: &CompositeParams, padding_levels: [u32; 5], (quote_font_size, author_font_size): (u32, u32)) -> (String, (i64, i64)) {
+	let CompositeParams {
+		safe_area,
+		line_spacing,
+		tab_width,
+		scrim_color,
+		..
+	} = *params;
+
+	let char_width_quote = ((quote_font_size as f32 * 0.6) as u32).max(1);
+	let mut raw_lines: Vec<String> = block.text.map(|t| t.lines().map(|l| sanitize_line(l, tab_width)).collect()).unwrap_or_default();
+	let max_chars = ((safe_area.width / 2).saturating_sub(padding_levels[0] * 2) / char_width_quote) as usize;
+	// Leave room for the marks, which go on after truncating
+	let max_chars = max_chars.saturating_sub(params.quote_marks.map_or(0, |_| 1));
+	truncate_lines(&mut raw_lines, params.quote_max_lines.unwrap_or(usize::MAX), max_chars);
+	if let Some((open, close)) = params.quote_marks
+		&& !raw_lines.is_empty()
+	{
+		let last = raw_lines.len() - 1;
+		raw_lines[0].insert(0, open);
+		raw_lines[last].push(close);
+	}
+	let text_width = raw_lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) as u32 * char_width_quote;
+	let line_height = (quote_font_size as f32 * line_spacing).round() as u32;
+	let quote_height = raw_lines.len() as u32 * line_height;
+	let author = block.author.map(|a| format!("© {}", sanitize_line(a, tab_width)));
+	let author_width = author.as_ref().map_or(0, |a| a.chars().count() as u32 * (author_font_size as f32 * 0.6) as u32);
+	let author_gap = if raw_lines.is_empty() { 0 } else { padding_levels[1] };
+	let block_height = quote_height + author.as_ref().map_or(0, |_| author_gap + author_font_size);
+
+	// Left corners align everything on the left edge; right ones on the right edge, like the main block
+	let (quote_x, author_x, author_anchor, block_left, block_right) = match corner {
+		Corner::TopLeft | Corner::BottomLeft => {
+			let left = safe_area.x + padding_levels[0];
+			(left, left, "start", left, left + text_width.max(author_width))
+		}
+		Corner::TopRight | Corner::BottomRight => {
+			let right = (safe_area.x + safe_area.width).saturating_sub(padding_levels[0]);
+			(right.saturating_sub(text_width), right, "end", right.saturating_sub(text_width.max(author_width)), right)
+		}
+	};
+	let quote_y = match corner {
+		Corner::TopLeft | Corner::TopRight => safe_area.y + padding_levels[0] * 2,
+		Corner::BottomLeft | Corner::BottomRight => (safe_area.y + safe_area.height).saturating_sub(padding_levels[0] + block_height) + quote_font_size,
+	};
+	let author_y = if raw_lines.is_empty() { quote_y } else { quote_y + quote_height + padding_levels[1] };
 
-	// Generate SVG with just the text elements (no background)
-	let svg_content = generate_text_svg(params.text, params.author, params.balance, params.width, params.height, params.safe_area, params.text_padding)?;
+	let tspans: String = raw_lines
+		.iter()
+		.enumerate()
+		.map(|(i, line)| {
+			let dy = if i == 0 { "0".to_owned() } else { format!("{line_spacing}em") };
+			format!(r#"<tspan x="{quote_x}" dy="{dy}">{}</tspan>"#, escape_xml(line))
+		})
+		.collect::<Vec<_>>()
+		.join("\n      ");
+	let quote_style = block.color.map(|color| format!(r#" style="fill: {};""#, escape_xml(color))).unwrap_or_default();
+
+	let mut elements = Vec::new();
+	if let Some(scrim_color) = scrim_color {
+		let bottom = if author.is_some() { author_y } else { quote_y + quote_height.saturating_sub(line_height) };
+		let scrim_x = block_left.saturating_sub(padding_levels[1]);
+		let scrim_y = quote_y.saturating_sub(quote_font_size + padding_levels[1]);
+		elements.push(format!(
+			r#"<rect x="{scrim_x}" y="{scrim_y}" width="{}" height="{}" rx="{}" fill="{}"/>"#,
+			block_right + padding_levels[1] - scrim_x,
+			(bottom + padding_levels[1]).saturating_sub(scrim_y),
+			padding_levels[2],
+			escape_xml(scrim_color)
+		));
+	}
+	if !raw_lines.is_empty() {
+		elements.push(format!(
+			r#"<text class="quote" x="{quote_x}" y="{quote_y}"{quote_style}>
+      {tspans}
+  </text>"#
+		));
+	}
+	if let Some(author) = author {
+		elements.push(format!(
+			r#"<text class="author" x="{author_x}" y="{author_y}" style="text-anchor: {author_anchor};">{}</text>"#,
+			escape_xml(&author)
+		));
+	}
+	(elements.join("\n  "), (quote_x.into(), quote_y.into()))
+}
+
+/// Which parts of the overlay resvg smooths the edges of
+#[derive(Clone, Copy, Debug)]
+struct Antialiasing {
+	text: bool,
+	shapes: bool,
+}
+
+impl Default for Antialiasing {
+	fn default() -> Self {
+		Self { text: true, shapes: true }
+	}
+}
 
+impl Antialiasing {
+	fn from_config(config: &AppConfig) -> Self {
+		Self {
+			text: config.text_antialiasing.unwrap_or(true),
+			shapes: config.shape_antialiasing.unwrap_or(true),
+		}
+	}
+}
+
+fn usvg_options(font_dirs: &[PathBuf], antialiasing: Antialiasing) -> usvg::Options<'static> {
 	// Set up font database for usvg
 	let mut fontdb = fontdb::Database::new();
 	fontdb.load_system_fonts();
@@ -864,6 +3545,14 @@ fn composite_text_on_image(params: &CompositeParams) -> Result<()> {
 		}
 	}
 
+	for dir in font_dirs {
+		if dir.is_dir() {
+			fontdb.load_fonts_dir(dir);
+		} else {
+			warn!("Font directory does not exist: {}", dir.display());
+		}
+	}
+
 	// Try to load DejaVu Sans Mono from common locations (for dev environment)
 	let dev_font_path = std::env::current_dir().ok().map(|p| p.join("assets/DejaVuSansMono.ttf"));
 	if let Some(path) = dev_font_path
@@ -873,38 +3562,1045 @@ fn composite_text_on_image(params: &CompositeParams) -> Result<()> {
 		warn!(?e) // Ignore errors, - means system fonts are already loaded
 	}
 
-	let options = usvg::Options {
+	// These are only defaults, so an element's own `text-rendering`/`shape-rendering` still wins
+	usvg::Options {
 		fontdb: Arc::new(fontdb),
+		text_rendering: match antialiasing.text {
+			true => usvg::TextRendering::OptimizeLegibility,
+			false => usvg::TextRendering::OptimizeSpeed,
+		},
+		shape_rendering: match antialiasing.shapes {
+			true => usvg::ShapeRendering::GeometricPrecision,
+			false => usvg::ShapeRendering::CrispEdges,
+		},
 		..Default::default()
+	}
+}
+
+/// Renders an SVG to a transparent pixmap of the given size.
+fn render_svg(tree: &usvg::Tree, width: u32, height: u32, transform: tiny_skia::Transform) -> Result<tiny_skia::Pixmap> {
+	let mut pixmap = tiny_skia::Pixmap::new(width, height).context("Failed to create pixmap")?;
+	resvg::render(tree, transform, &mut pixmap.as_mut());
+	Ok(pixmap)
+}
+
+/// Alpha-blends a rendered layer onto the image, in place.
+fn blend_layer(bg_image: &mut image::RgbaImage, layer: &tiny_skia::Pixmap) -> Result<()> {
+	for y in 0..layer.height() {
+		for x in 0..layer.width() {
+			let text_pixel = layer.pixel(x, y).context("Failed to get pixel")?;
+			if text_pixel.alpha() > 0 {
+				let src = [text_pixel.red(), text_pixel.green(), text_pixel.blue(), text_pixel.alpha()].map(f32::from);
+				blend_pixel(bg_image.get_pixel_mut(x, y), src);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// [`blend_layer`] for a layer rendered at `scale` of the image's size, sampled back up bilinearly as it's blended,
+/// so no full-size copy of it is ever allocated.
+fn blend_layer_scaled(bg_image: &mut image::RgbaImage, layer: &tiny_skia::Pixmap, scale: f32) -> Result<()> {
+	let (max_x, max_y) = (layer.width() - 1, layer.height() - 1);
+	let (width, height) = bg_image.dimensions();
+	for y in 0..height {
+		let fy = ((y as f32 + 0.5) * scale - 0.5).clamp(0.0, max_y as f32);
+		let (y0, ty) = (fy as u32, fy.fract());
+		let y1 = (y0 + 1).min(max_y);
+		for x in 0..width {
+			let fx = ((x as f32 + 0.5) * scale - 0.5).clamp(0.0, max_x as f32);
+			let (x0, tx) = (fx as u32, fx.fract());
+			let x1 = (x0 + 1).min(max_x);
+
+			// Premultiplied, so interpolating the channels straight is right
+			let mut src = [0.0_f32; 4];
+			for (sx, sy, weight) in [(x0, y0, (1.0 - tx) * (1.0 - ty)), (x1, y0, tx * (1.0 - ty)), (x0, y1, (1.0 - tx) * ty), (x1, y1, tx * ty)] {
+				let pixel = layer.pixel(sx, sy).context("Failed to get pixel")?;
+				for (channel, value) in [pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()].into_iter().enumerate() {
+					src[channel] += value as f32 * weight;
+				}
+			}
+			if src[3] > 0.0 {
+				blend_pixel(bg_image.get_pixel_mut(x, y), src);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Porter-Duff "over" of a premultiplied (0-255) `src` onto a straight-alpha background pixel, alpha included,
+/// so transparent backgrounds come out right too.
+fn blend_pixel(bg_pixel: &mut image::Rgba<u8>, src: [f32; 4]) {
+	let src_alpha = src[3] / 255.0;
+	let dst_alpha = bg_pixel[3] as f32 / 255.0;
+	let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+	for (channel, src) in src[..3].iter().enumerate() {
+		let premultiplied = src + bg_pixel[channel] as f32 * dst_alpha * (1.0 - src_alpha);
+		bg_pixel[channel] = (premultiplied / out_alpha).round().min(255.0) as u8;
+	}
+	bg_pixel[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Text falling outside the canvas is clipped silently by the renderer; say by how much, so it's not just lost.
+fn warn_on_overflow(tree: &usvg::Tree, width: u32, height: u32) {
+	let bbox = tree.root().abs_bounding_box();
+	let left = (-bbox.left()).max(0.0);
+	let top = (-bbox.top()).max(0.0);
+	let right = (bbox.right() - width as f32).max(0.0);
+	let bottom = (bbox.bottom() - height as f32).max(0.0);
+	if left + top + right + bottom > 0.5 {
+		v_utils::elog!("Warning: text overflows the {width}x{height} image and gets clipped (by {left:.0}px left, {top:.0}px top, {right:.0}px right, {bottom:.0}px bottom)");
+	}
+}
+
+/// The safe area of a `width`x`height` image, as seen from the frame turned `rotation` degrees clockwise.
+fn rotate_safe_area(area: &SafeArea, width: u32, height: u32, rotation: u16) -> SafeArea {
+	match rotation {
+		90 => SafeArea {
+			x: area.y,
+			y: width - area.x - area.width,
+			width: area.height,
+			height: area.width,
+		},
+		180 => SafeArea {
+			x: width - area.x - area.width,
+			y: height - area.y - area.height,
+			width: area.width,
+			height: area.height,
+		},
+		270 => SafeArea {
+			x: height - area.y - area.height,
+			y: area.x,
+			width: area.height,
+			height: area.width,
+		},
+		_ => area.clone(),
+	}
+}
+
+/// Maps the frame turned `rotation` degrees clockwise back onto the `width`x`height` image.
+fn rotation_transform(rotation: u16, width: u32, height: u32) -> tiny_skia::Transform {
+	let (width, height) = (width as f32, height as f32);
+	match rotation {
+		90 => tiny_skia::Transform::from_row(0.0, 1.0, -1.0, 0.0, width, 0.0),
+		180 => tiny_skia::Transform::from_row(-1.0, 0.0, 0.0, -1.0, width, height),
+		270 => tiny_skia::Transform::from_row(0.0, -1.0, 1.0, 0.0, 0.0, height),
+		_ => tiny_skia::Transform::default(),
+	}
+}
+
+fn load_overlay_image(overlay: &OverlayImage) -> Result<image::RgbaImage> {
+	let logo = image::open(&overlay.path).wrap_err_with(|| format!("Failed to open overlay image {}", overlay.path.display()))?;
+	let scale = overlay.scale.unwrap_or(1.0);
+	if scale == 1.0 {
+		return Ok(logo.to_rgba8());
+	}
+	let width = ((logo.width() as f32 * scale).round() as u32).max(1);
+	let height = ((logo.height() as f32 * scale).round() as u32).max(1);
+	Ok(logo.resize_exact(width, height, image::imageops::FilterType::Lanczos3).to_rgba8())
+}
+
+/// Alpha-blends `logo` into its corner of the safe area, level 0 padding away from the edges.
+fn place_overlay_image(bg_image: &mut image::RgbaImage, logo: &image::RgbaImage, overlay: &OverlayImage, params: &CompositeParams) {
+	let SafeArea { x, y, width, height } = *params.safe_area;
+	let padding = params.text_padding as i64;
+	let (left, top) = (x as i64 + padding, y as i64 + padding);
+	let right = (x + width) as i64 - padding - logo.width() as i64;
+	let bottom = (y + height) as i64 - padding - logo.height() as i64;
+	let (logo_x, logo_y) = match overlay.position.unwrap_or_default() {
+		Corner::TopLeft => (left, top),
+		Corner::TopRight => (right, top),
+		Corner::BottomLeft => (left, bottom),
+		Corner::BottomRight => (right, bottom),
+	};
+	image::imageops::overlay(bg_image, logo, logo_x, logo_y);
+}
+
+fn composite_text_on_image(params: &CompositeParams, timer: &mut StageTimer) -> Result<(image::RgbaImage, TextLayout)> {
+	// Load background image
+	let mut bg_image = image::open(params.bg_image_path)?.to_rgba8();
+	timer.lap("load temp background");
+
+	// Lay the text out upright in the rotated frame; rendering turns it back into the image's
+	let (canvas_width, canvas_height) = match params.rotation {
+		90 | 270 => (params.height, params.width),
+		_ => (params.width, params.height),
+	};
+	let canvas_safe_area = rotate_safe_area(params.safe_area, params.width, params.height, params.rotation);
+	let canvas = CompositeParams {
+		width: canvas_width,
+		height: canvas_height,
+		safe_area: &canvas_safe_area,
+		..*params
 	};
 
-	let tree = usvg::Tree::from_str(&svg_content, &options)?;
+	// Generate SVG with just the text elements (no background)
+	let (svg_content, layout) = generate_text_svg(&canvas)?;
+	timer.lap("svg generation");
+	let tree = usvg::Tree::from_str(&svg_content, &usvg_options(params.font_dirs, params.antialiasing))?;
+	timer.lap("svg parsing and font loading");
+	warn_on_overflow(&tree, canvas_width, canvas_height);
+
+	// Render text SVG to a transparent pixmap, then composite it onto the background image, with the logo under or over it
+	let scale = params.render_scale;
+	let transform = rotation_transform(params.rotation, params.width, params.height).post_scale(scale, scale);
+	let layer_width = ((params.width as f32 * scale).ceil() as u32).max(1);
+	let layer_height = ((params.height as f32 * scale).ceil() as u32).max(1);
+	let text_pixmap = render_svg(&tree, layer_width, layer_height, transform)?;
+	timer.lap("render");
+	let overlay_image = params.overlay_image.map(|overlay| load_overlay_image(overlay).map(|logo| (overlay, logo))).transpose()?;
+	if let Some((overlay, logo)) = &overlay_image
+		&& !overlay.above_text
+	{
+		place_overlay_image(&mut bg_image, logo, overlay, params);
+	}
+	if scale < 1.0 {
+		blend_layer_scaled(&mut bg_image, &text_pixmap, scale)?;
+	} else {
+		blend_layer(&mut bg_image, &text_pixmap)?;
+	}
+	if let Some((overlay, logo)) = &overlay_image
+		&& overlay.above_text
+	{
+		place_overlay_image(&mut bg_image, logo, overlay, params);
+	}
+	timer.lap("composite");
 
-	// Render text SVG to a transparent pixmap
-	let mut text_pixmap = tiny_skia::Pixmap::new(params.width, params.height).context("Failed to create pixmap")?;
+	Ok((bg_image, layout))
+}
 
-	resvg::render(&tree, tiny_skia::Transform::default(), &mut text_pixmap.as_mut());
+/// Renders the wallpaper as it would be generated, with the safe area (red) and each text block's bounding box (cyan) outlined.
+fn debug_safe_area(input_path: &Path, output_path: &Path, config: &AppConfig, options: &GenerateOptions) -> Result<()> {
+	let all_displays = get_all_active_displays(options.outputs_from.as_deref(), config.ignore_outputs.as_deref().unwrap_or_default())?;
+	let (display_width, display_height) = get_display_resolution(&all_displays)?;
+	let (img, _) = open_with_icc(input_path, None)?;
+	let mut bg_image = resize_fill(img, display_width, display_height, config.resize_filter.unwrap_or_default().filter_type()).to_rgba8();
+	let (img_width, img_height) = bg_image.dimensions();
+
+	let safe_area = calculate_safe_area(img_width, img_height, &safe_area_displays(&all_displays, config.safe_area_min_width));
+	v_utils::elog!("Safe area: {safe_area:?}");
+
+	let quote = select_quote(config.active_quotes(local_time().tm_hour as u32), config.selection_mode.unwrap_or_default()).context("No quotes configured")?;
+	let balances = collect_balances(config, None);
+	let params = CompositeParams {
+		bg_image_path: input_path,
+		text: Some(&quote.text),
+		author: quote.author.as_deref(),
+		source: quote.source.as_deref(),
+		balances: &balances,
+		width: img_width,
+		height: img_height,
+		safe_area: &safe_area,
+		text_padding: config.text_padding.unwrap_or(Padding::Pixels(15)).resolve(safe_area.width, safe_area.height),
+		quote_max_lines: config.quote_max_lines,
+		line_spacing: config.line_spacing.unwrap_or(1.2),
+		tab_width: config.tab_width.unwrap_or(4),
+		font_dirs: config.font_dirs.as_deref().unwrap_or_default(),
+		text_color: config.text_color.as_deref().unwrap_or("white"),
+		scrim_color: config.scrim_color.as_deref(),
+		quote_color: quote.color.as_deref(),
+		opacity: TextOpacity::from_config(config),
+		// The outlines below are in image coordinates, so keep the text there too
+		rotation: 0,
+		extra_quotes: &[],
+		timestamp: None,
+		caption: None,
+		overlay_image: config.overlay_image.as_ref(),
+		max_overlay_fraction: config.max_overlay_fraction,
+		quote_marks: config.quote_marks(),
+		antialiasing: Antialiasing::from_config(config),
+		render_scale: config.overlay_render_scale.unwrap_or(1.0).clamp(0.1, 1.0),
+		block_cell: None,
+	};
 
-	// Composite text layer onto background image
-	for y in 0..params.height {
-		for x in 0..params.width {
-			let text_pixel = text_pixmap.pixel(x, y).context("Failed to get pixel")?;
-			let alpha = text_pixel.alpha();
+	let options = usvg_options(params.font_dirs, params.antialiasing);
+	let text_tree = usvg::Tree::from_str(&generate_text_svg(&params)?.0, &options)?;
+
+	let mut rects = vec![format!(
+		r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="red" stroke-width="4"/>"#,
+		safe_area.x, safe_area.y, safe_area.width, safe_area.height
+	)];
+	for node in text_tree.root().children() {
+		let bbox = node.abs_bounding_box();
+		v_utils::elog!("Text block: x={:.0}, y={:.0}, width={:.0}, height={:.0}", bbox.x(), bbox.y(), bbox.width(), bbox.height());
+		rects.push(format!(
+			r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="cyan" stroke-width="2"/>"#,
+			bbox.x(),
+			bbox.y(),
+			bbox.width(),
+			bbox.height()
+		));
+	}
+	let rects_svg = format!(r#"<svg width="{img_width}" height="{img_height}" xmlns="http://www.w3.org/2000/svg">{}</svg>"#, rects.join(""));
+	let rects_tree = usvg::Tree::from_str(&rects_svg, &options)?;
 
-			if alpha > 0 {
-				let bg_pixel = bg_image.get_pixel_mut(x, y);
-				let alpha_f = alpha as f32 / 255.0;
+	blend_layer(&mut bg_image, &render_svg(&text_tree, img_width, img_height, tiny_skia::Transform::default())?)?;
+	blend_layer(&mut bg_image, &render_svg(&rects_tree, img_width, img_height, tiny_skia::Transform::default())?)?;
+	bg_image.save(output_path)?;
 
-				// Alpha blending
-				bg_pixel[0] = ((text_pixel.red() as f32 * alpha_f) + (bg_pixel[0] as f32 * (1.0 - alpha_f))) as u8;
-				bg_pixel[1] = ((text_pixel.green() as f32 * alpha_f) + (bg_pixel[1] as f32 * (1.0 - alpha_f))) as u8;
-				bg_pixel[2] = ((text_pixel.blue() as f32 * alpha_f) + (bg_pixel[2] as f32 * (1.0 - alpha_f))) as u8;
+	v_utils::log!("Safe area debug image written to {}", output_path.display());
+	Ok(())
+}
+
+/// Whether text over `area` would be hard to read without a scrim: the wrong brightness for the text, or busy enough
+/// (high luminance spread) to break letters up. Samples a grid of at most ~40k pixels.
+fn needs_scrim(img: &image::DynamicImage, area: &SafeArea, dark_text: bool) -> bool {
+	let step = ((area.width as f32 * area.height as f32 / 40_000.0).sqrt() as usize).max(1);
+	let (mut sum, mut sum_sq, mut n) = (0.0_f64, 0.0_f64, 0_u32);
+	for y in (area.y..(area.y + area.height).min(img.height())).step_by(step) {
+		for x in (area.x..(area.x + area.width).min(img.width())).step_by(step) {
+			let [r, g, b, _] = img.get_pixel(x, y).0;
+			// Rec. 709 luma
+			let luma = (0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64) / 255.0;
+			sum += luma;
+			sum_sq += luma * luma;
+			n += 1;
+		}
+	}
+	if n == 0 {
+		return false;
+	}
+	let mean = sum / n as f64;
+	let spread = (sum_sq / n as f64 - mean * mean).max(0.0).sqrt();
+	let wrong_brightness = if dark_text { mean < 0.5 } else { mean > 0.5 };
+	wrong_brightness || spread > 0.2
+}
+
+/// The rule-of-thirds cell (column, row) of `area` with the least detail, by mean luminance gradient. Samples a grid of at
+/// most ~40k pixels; ties go to the top-right, where the block would be anyway.
+fn calmest_thirds_cell(img: &image::DynamicImage, area: &SafeArea) -> (u32, u32) {
+	let step = ((area.width as f32 * area.height as f32 / 40_000.0).sqrt() as u32).max(1);
+	let luma = |x, y| {
+		let [r, g, b, _] = img.get_pixel(x, y).0;
+		0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64
+	};
+	let right = (area.x + area.width).min(img.width());
+	let bottom = (area.y + area.height).min(img.height());
+	let mut energy = [[(0.0_f64, 0_u32); 3]; 3];
+	for y in (area.y..bottom.saturating_sub(step)).step_by(step as usize) {
+		for x in (area.x..right.saturating_sub(step)).step_by(step as usize) {
+			let here = luma(x, y);
+			let gradient = (luma(x + step, y) - here).abs() + (luma(x, y + step) - here).abs();
+			let cell = &mut energy[((y - area.y) * 3 / area.height) as usize][((x - area.x) * 3 / area.width) as usize];
+			cell.0 += gradient;
+			cell.1 += 1;
+		}
+	}
+
+	let mut calmest = (2, 0);
+	let mut lowest = f64::INFINITY;
+	for row in 0..3 {
+		for column in (0..3).rev() {
+			let (sum, n) = energy[row as usize][column as usize];
+			if n > 0 && sum / (n as f64) < lowest {
+				lowest = sum / n as f64;
+				calmest = (column, row);
 			}
 		}
 	}
+	calmest
+}
+
+/// Rule-of-thirds `cell` (column, row) of `area`
+fn thirds_cell(area: &SafeArea, (column, row): (u32, u32)) -> SafeArea {
+	let x = area.x + area.width * column / 3;
+	let y = area.y + area.height * row / 3;
+	SafeArea {
+		x,
+		y,
+		width: area.x + area.width * (column + 1) / 3 - x,
+		height: area.y + area.height * (row + 1) / 3 - y,
+	}
+}
 
-	// Save the composited image
-	bg_image.save(params.output_path)?;
+/// How far the main block, laid out in the top-right corner of `area` and spanning `block` (left, top, right, bottom), moves to
+/// sit in `cell` instead: against the safe area's (padded) edge in the outer columns/rows, centered on the cell in the middle
+/// ones. Never out of the safe area, unless the block is bigger than it.
+fn thirds_offset((left, top, right, bottom): (i64, i64, i64, i64), (column, row): (u32, u32), area: &SafeArea, padding: u32) -> (i64, i64) {
+	let padding = i64::from(padding);
+	let (area_left, area_top) = (area.x as i64, area.y as i64);
+	let (area_right, area_bottom) = (area_left + area.width as i64, area_top + area.height as i64);
+	let dx = match column {
+		0 => area_left + padding - left,
+		1 => area_left + area.width as i64 / 2 - (left + right) / 2,
+		_ => 0,
+	};
+	let dy = match row {
+		0 => 0,
+		1 => area_top + area.height as i64 / 2 - (top + bottom) / 2,
+		_ => area_bottom - bottom,
+	};
+	// Bottom first, so a block taller than the safe area still starts at its top
+	let dx = dx.min(area_right - right).max(area_left - left);
+	let dy = dy.min(area_bottom - bottom).max(area_top - top);
+	(dx, dy)
+}
 
+/// Longest side of `browse` previews, in pixels
+const PREVIEW_MAX_SIDE: u32 = 960;
+
+/// The wallpaper as it would be generated, shrunk to fit `max_side`. The layout is done at full display resolution and only
+/// the rendering is scaled, so the text sits where it would on screen; the background goes through the fast thumbnail path.
+fn render_preview(input_path: &Path, config: &AppConfig, options: &GenerateOptions, max_side: u32) -> Result<image::RgbaImage> {
+	let all_displays = get_all_active_displays(options.outputs_from.as_deref(), config.ignore_outputs.as_deref().unwrap_or_default())?;
+	let (display_width, display_height) = get_display_resolution(&all_displays)?;
+	let scale = (max_side as f32 / display_width.max(display_height) as f32).min(1.0);
+	let (preview_width, preview_height) = (((display_width as f32 * scale) as u32).max(1), ((display_height as f32 * scale) as u32).max(1));
+	let (img, _) = open_with_icc(input_path, options.frame)?;
+	let mut bg_image = thumbnail_fill(img, preview_width, preview_height).to_rgba8();
+
+	let safe_area = calculate_safe_area(display_width, display_height, &safe_area_displays(&all_displays, config.safe_area_min_width));
+	let quote = select_quote(config.active_quotes(local_time().tm_hour as u32), config.selection_mode.unwrap_or_default()).context("No quotes configured")?;
+	// Previews get re-rendered on every keypress, so don't run the balance commands for them
+	let params = CompositeParams {
+		bg_image_path: input_path,
+		text: Some(&quote.text),
+		author: quote.author.as_deref(),
+		source: quote.source.as_deref(),
+		balances: &[],
+		width: display_width,
+		height: display_height,
+		safe_area: &safe_area,
+		text_padding: config.text_padding.unwrap_or(Padding::Pixels(15)).resolve(safe_area.width, safe_area.height),
+		quote_max_lines: config.quote_max_lines,
+		line_spacing: config.line_spacing.unwrap_or(1.2),
+		tab_width: config.tab_width.unwrap_or(4),
+		font_dirs: config.font_dirs.as_deref().unwrap_or_default(),
+		text_color: config.text_color.as_deref().or(options.theme.map(Theme::text_color)).unwrap_or("white"),
+		scrim_color: config.scrim_color.as_deref().or(options.theme.map(Theme::scrim_color)),
+		quote_color: quote.color.as_deref(),
+		opacity: TextOpacity::from_config(config),
+		rotation: 0,
+		extra_quotes: &[],
+		timestamp: None,
+		caption: None,
+		overlay_image: None,
+		max_overlay_fraction: config.max_overlay_fraction,
+		quote_marks: config.quote_marks(),
+		antialiasing: Antialiasing::from_config(config),
+		render_scale: config.overlay_render_scale.unwrap_or(1.0).clamp(0.1, 1.0),
+		block_cell: None,
+	};
+	let tree = usvg::Tree::from_str(&generate_text_svg(&params)?.0, &usvg_options(params.font_dirs, params.antialiasing))?;
+	blend_layer(&mut bg_image, &render_svg(&tree, preview_width, preview_height, tiny_skia::Transform::from_scale(scale, scale))?)?;
+	Ok(bg_image)
+}
+
+/// Minimal line-based picker: shows the current file name, moves on `n`/`p`, applies it (overlay included) on enter.
+/// Each image shown is also previewed into `preview.png` in the state dir, for an auto-reloading viewer to pick up.
+fn browse(directory: &Path, config: &AppConfig, options: &GenerateOptions) -> Result<()> {
+	use std::io::BufRead as _;
+
+	let listing = ListingOptions {
+		allow_single: true,
+		dedupe_by_hash: config.dedupe_by_hash.unwrap_or(false),
+		..Default::default()
+	};
+	let files = list_images(directory, listing)?;
+	let mut current = files.first().with_context(|| format!("No images in {}", directory.display()))?.clone();
+
+	let preview_path = get_preview_file_path();
+	let stdin = std::io::stdin();
+	let mut lines = stdin.lock().lines();
+	loop {
+		let position = files.iter().position(|f| *f == current).map_or(0, |i| i + 1);
+		let name = current.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+		match render_preview(&current, config, options, PREVIEW_MAX_SIDE).and_then(|preview| Ok(preview.save(&preview_path)?)) {
+			Ok(()) => eprintln!("Preview: {}", preview_path.display()),
+			Err(e) => warn!("Failed to preview {name}: {e}"),
+		}
+		eprint!("[{position}/{}] {name}  (n)ext, (p)revious, enter to apply, (q)uit: ", files.len());
+		let Some(line) = lines.next().transpose()? else {
+			return Ok(());
+		};
+		match line.trim() {
+			"n" | "j" => current = find_next_image(&current, false, Some(directory), listing)?,
+			"p" | "k" => current = find_next_image(&current, true, Some(directory), listing)?,
+			"" => {
+				let result = with_lock(config.lock_policy.unwrap_or_default(), || generate_wallpaper(Some(&current), config, options));
+				save_last_input(&current)?;
+				return result;
+			}
+			"q" => return Ok(()),
+			other => eprintln!("Unknown choice {other:?}"),
+		}
+	}
+}
+
+/// Runs every environment check, printing a pass/fail line for each; errors if any failed.
+fn doctor(config: Result<AppConfig>) -> Result<()> {
+	fn find_in_path(program: &str) -> Result<String> {
+		std::env::var_os("PATH")
+			.iter()
+			.flat_map(std::env::split_paths)
+			.map(|dir| dir.join(program))
+			.find(|path| path.is_file())
+			.map(|path| path.display().to_string())
+			.with_context(|| format!("`{program}` not found in PATH"))
+	}
+
+	let font_dirs = config.as_ref().ok().and_then(|c| c.font_dirs.clone()).unwrap_or_default();
+	let mut checks: Vec<(&str, Result<String>)> = vec![("config", config.as_ref().map(|c| format!("{} quote(s)", c.quotes.len())).map_err(|e| eyre!("{e:#}")))];
+	match display_server() {
+		DisplayServer::Sway => {
+			let sway_socket = (|| -> Result<String> {
+				let socket = std::env::var("SWAYSOCK").context("SWAYSOCK is not set; is sway running?")?;
+				if !Path::new(&socket).exists() {
+					bail!("SWAYSOCK points to {socket}, which doesn't exist");
+				}
+				let output = ProcessCommand::new("swaymsg").args(["-t", "get_version"]).output()?;
+				if !output.status.success() {
+					bail!("swaymsg -t get_version failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+				}
+				Ok(socket)
+			})();
+			checks.extend([("swaymsg", find_in_path("swaymsg")), ("sway socket", sway_socket)]);
+		}
+		DisplayServer::X11 => checks.extend([("xrandr", find_in_path("xrandr")), ("feh", find_in_path("feh"))]),
+	}
+	checks.extend([
+		("typst", find_in_path("typst")),
+		(
+			"monospace font",
+			(|| -> Result<String> {
+				let options = usvg_options(&font_dirs, Antialiasing::default());
+				let query = fontdb::Query {
+					families: &[fontdb::Family::Name("DejaVu Sans Mono"), fontdb::Family::Monospace],
+					..Default::default()
+				};
+				let id = options.fontdb.query(&query).context("Neither DejaVu Sans Mono nor any other monospace font found")?;
+				let face = options.fontdb.face(id).context("Font face vanished")?;
+				Ok(face.families.first().map(|(name, _)| name.clone()).unwrap_or_else(|| face.post_script_name.clone()))
+			})(),
+		),
+		(
+			"vision bundle",
+			get_vision_paths().map(|(vision_png, src_typ)| format!("{} (sources: {})", vision_png.display(), src_typ.display())),
+		),
+	]);
+
+	report(checks)
+}
+
+/// Lint for the config: everything that would only fail once it's used, checked up front. Reports like [`doctor`].
+fn config_check(config: &AppConfig) -> Result<()> {
+	let dir_exists = |what: &str, dir: &Path| -> Result<String> {
+		match dir.is_dir() {
+			true => Ok(dir.display().to_string()),
+			false => bail!("{what} {} is not a directory", dir.display()),
+		}
+	};
+
+	let mut checks: Vec<(String, Result<String>)> = vec![(
+		"quotes".to_owned(),
+		Ok(format!("{} quote(s), {} pool(s)", config.quotes.len(), config.pools.as_ref().map_or(0, Vec::len))),
+	)];
+	for balance in config.balance.iter().flat_map(|balances| balances.ordered()) {
+		let name = format!("balance {:?}", balance.label.as_deref().unwrap_or(&balance.command));
+		// `sh -n` parses without executing anything
+		let result = match ProcessCommand::new("sh").arg("-n").arg("-c").arg(&balance.command).output() {
+			Ok(output) if output.status.success() => Ok("command parses".to_owned()),
+			Ok(output) => Err(eyre!("command doesn't parse: {}", String::from_utf8_lossy(&output.stderr).trim())),
+			Err(e) => Err(e.into()),
+		};
+		checks.push((name, result));
+	}
+	if let Some(pattern) = &config.caption_pattern {
+		checks.push(("caption_pattern".to_owned(), regex::Regex::new(pattern).map(|_| pattern.clone()).map_err(Into::into)));
+	}
+	if let Some(chars) = &config.quote_mark_chars {
+		let result = match chars.chars().count() {
+			2 => Ok(chars.clone()),
+			_ => Err(eyre!("expected exactly two characters (opening, closing), got {chars:?}")),
+		};
+		checks.push(("quote_mark_chars".to_owned(), result));
+	}
+	if let Some(dir) = &config.generate_background_dir {
+		checks.push(("generate_background_dir".to_owned(), dir_exists("Background directory", dir)));
+	}
+	for source in config.sources.iter().flatten() {
+		checks.push(("sources".to_owned(), dir_exists("Source", &source.dir)));
+	}
+	for dir in config.font_dirs.iter().flatten() {
+		checks.push(("font_dirs".to_owned(), dir_exists("Font directory", dir)));
+	}
+	if let Some(overlay) = &config.overlay_image {
+		checks.push(("overlay_image".to_owned(), load_overlay_image(overlay).map(|logo| format!("{}x{}", logo.width(), logo.height()))));
+	}
+
+	report(checks)
+}
+
+/// Prints an `[ok]`/`[FAIL]` line per check, for [`doctor`] and [`config_check`]; errors if any failed.
+fn report<N: std::fmt::Display>(checks: Vec<(N, Result<String>)>) -> Result<()> {
+	let mut failed = 0;
+	for (name, result) in checks {
+		match result {
+			Ok(detail) => println!("[ok]   {name}: {detail}"),
+			Err(e) => {
+				failed += 1;
+				println!("[FAIL] {name}: {e}");
+			}
+		}
+	}
+	if failed > 0 {
+		bail!("{failed} check(s) failed");
+	}
 	Ok(())
 }
+
+fn parse_dim(s: &str) -> std::result::Result<f32, String> {
+	s.trim()
+		.parse::<f32>()
+		.ok()
+		.filter(|v| (0.0..=1.0).contains(v))
+		.ok_or_else(|| format!("Expected a number from 0.0 to 1.0, got {s:?}"))
+}
+
+/// Sets the last generated wallpaper again, darkened by `amount`. Always starts from the undimmed one, so dims don't stack.
+fn dim(amount: f32, config: &AppConfig) -> Result<()> {
+	let current = v_utils::xdg_state_file!("extended.png").with_extension(config.output_format.unwrap_or_default().extension());
+	if !current.exists() {
+		bail!("No generated wallpaper to dim yet (looked for {})", current.display());
+	}
+	if amount == 0.0 {
+		set_wallpaper(&current, config, None)?;
+		v_utils::log!("Wallpaper restored");
+		return Ok(());
+	}
+
+	let (img, icc_profile) = open_with_icc(&current, None)?;
+	let mut img = img.to_rgba8();
+	scale_brightness(&mut img, 1.0 - amount);
+	let dimmed_path = get_dimmed_file_path();
+	save_image_with_icc(
+		&img,
+		std::fs::File::create(&dimmed_path)?,
+		icc_profile.as_deref(),
+		OutputFormat::Png,
+		90,
+		WebpEncoding::Lossless,
+		config.output_dpi,
+	)?;
+	set_wallpaper(&dimmed_path, config, None)?;
+	v_utils::log!("Wallpaper dimmed by {:.0}%", amount * 100.0);
+	Ok(())
+}
+
+/// Multiplies every color channel by `factor`, leaving alpha be. Split over all cores, as it's run on full-resolution images.
+fn scale_brightness(img: &mut image::RgbaImage, factor: f32) {
+	let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+	// Whole pixels per chunk
+	let chunk_len = (img.len() / threads).div_ceil(4).max(1) * 4;
+	std::thread::scope(|s| {
+		for chunk in img.chunks_mut(chunk_len) {
+			s.spawn(move || {
+				for pixel in chunk.chunks_exact_mut(4) {
+					for channel in &mut pixel[..3] {
+						*channel = (*channel as f32 * factor).round() as u8;
+					}
+				}
+			});
+		}
+	});
+}
+
+fn parse_resolution(s: &str) -> std::result::Result<(u32, u32), String> {
+	let (width, height) = s.split_once('x').ok_or_else(|| format!("Expected WIDTHxHEIGHT, got {s:?}"))?;
+	let parse = |v: &str| v.trim().parse::<u32>().ok().filter(|&v| v > 0).ok_or_else(|| format!("Invalid dimension {v:?} in {s:?}"));
+	Ok((parse(width)?, parse(height)?))
+}
+
+/// Runs `resize_fill` + `composite_text_on_image` `iterations` times on a synthetic gradient, reporting mean/median/p99.
+///
+/// The source is 1.5x the target, so the resize actually does work. Includes writing the temp background, as `generate` does.
+fn benchmark((width, height): (u32, u32), iterations: usize, config: &AppConfig) -> Result<()> {
+	if iterations == 0 {
+		bail!("--iterations must be at least 1");
+	}
+	let (src_width, src_height) = (width * 3 / 2, height * 3 / 2);
+	let source = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(src_width, src_height, |x, y| {
+		image::Rgb([(x * 255 / src_width) as u8, (y * 255 / src_height) as u8, 128])
+	}));
+	let temp_bg = TempFile(std::env::temp_dir().join(format!("wallpaper_carousel_benchmark.{}.png", std::process::id())));
+
+	let fallback = Quote {
+		text: "The quick brown fox jumps over the lazy dog, and then does it again for good measure.".to_owned(),
+		author: Some("Benchmark".to_owned()),
+		source: None,
+		color: None,
+		tags: Vec::new(),
+	};
+	let quote = config.quotes.first().unwrap_or(&fallback);
+	let balances = [BalanceBlock {
+		text: "Balance\n12345.67".to_owned(),
+		font_size: 20,
+		color: None,
+	}];
+	let safe_area = calculate_safe_area(width, height, &[(width, height)]);
+
+	let mut timings = Vec::with_capacity(iterations);
+	for _ in 0..iterations {
+		let start = std::time::Instant::now();
+		let resized = resize_fill(source.clone(), width, height, config.resize_filter.unwrap_or_default().filter_type());
+		resized.save(&temp_bg.0)?;
+		composite_text_on_image(
+			&CompositeParams {
+				bg_image_path: &temp_bg.0,
+				text: Some(&quote.text),
+				author: quote.author.as_deref(),
+				source: quote.source.as_deref(),
+				balances: &balances,
+				width,
+				height,
+				safe_area: &safe_area,
+				text_padding: config.text_padding.unwrap_or(Padding::Pixels(15)).resolve(safe_area.width, safe_area.height),
+				quote_max_lines: config.quote_max_lines,
+				line_spacing: config.line_spacing.unwrap_or(1.2),
+				tab_width: config.tab_width.unwrap_or(4),
+				font_dirs: config.font_dirs.as_deref().unwrap_or_default(),
+				text_color: config.text_color.as_deref().unwrap_or("white"),
+				scrim_color: config.scrim_color.as_deref(),
+				quote_color: quote.color.as_deref(),
+				opacity: TextOpacity::from_config(config),
+				rotation: 0,
+				extra_quotes: &[],
+				timestamp: None,
+				caption: None,
+				overlay_image: config.overlay_image.as_ref(),
+				max_overlay_fraction: config.max_overlay_fraction,
+				quote_marks: config.quote_marks(),
+				antialiasing: Antialiasing::from_config(config),
+				render_scale: config.overlay_render_scale.unwrap_or(1.0).clamp(0.1, 1.0),
+				block_cell: None,
+			},
+			&mut StageTimer::new(false),
+		)?;
+		timings.push(start.elapsed());
+	}
+	drop(temp_bg);
+
+	timings.sort();
+	let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+	let mean = timings.iter().map(|&d| ms(d)).sum::<f64>() / iterations as f64;
+	let median = ms(timings[iterations / 2]);
+	let p99 = ms(timings[(iterations as f64 * 0.99).ceil() as usize - 1]);
+	println!("{width}x{height}, {iterations} iterations: mean {mean:.1}ms, median {median:.1}ms, p99 {p99:.1}ms");
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Fresh directory holding empty files with the given names
+	fn image_dir(name: &str, files: &[&str]) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("wallpaper_carousel_{name}_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		for file in files {
+			std::fs::write(dir.join(file), b"").unwrap();
+		}
+		dir
+	}
+
+	#[test]
+	fn next_index_wraps_and_falls_back() {
+		let files: Vec<PathBuf> = ["a.png", "b.png", "c.png"].iter().map(PathBuf::from).collect();
+
+		assert_eq!(next_index(&files, Path::new("c.png"), false), 0);
+		assert_eq!(next_index(&files, Path::new("a.png"), true), 2);
+		assert_eq!(next_index(&files, Path::new("a.png"), false), 1);
+		assert_eq!(next_index(&files, Path::new("elsewhere.png"), false), 0);
+		assert_eq!(next_index(&files, Path::new("elsewhere.png"), true), 2);
+	}
+
+	#[test]
+	fn find_next_image_wraps_around() {
+		let dir = image_dir("wraparound", &["a.png", "b.jpg", "c.png", "notes.txt"]);
+
+		assert_eq!(find_next_image(&dir.join("c.png"), false, None, ListingOptions::default()).unwrap(), dir.join("a.png"));
+		assert_eq!(find_next_image(&dir.join("a.png"), true, None, ListingOptions::default()).unwrap(), dir.join("c.png"));
+		assert_eq!(find_next_image(&dir.join("a.png"), false, None, ListingOptions::default()).unwrap(), dir.join("b.jpg"));
+	}
+
+	#[test]
+	fn find_next_image_current_not_in_dir() {
+		let dir = image_dir("not_in_dir", &["a.png", "b.png", "c.png"]);
+		let elsewhere = Path::new("/nonexistent/x.png");
+
+		assert_eq!(find_next_image(elsewhere, false, Some(&dir), ListingOptions::default()).unwrap(), dir.join("a.png"));
+		assert_eq!(find_next_image(elsewhere, true, Some(&dir), ListingOptions::default()).unwrap(), dir.join("c.png"));
+	}
+
+	#[test]
+	fn find_next_image_single_image() {
+		let dir = image_dir("single", &["a.png"]);
+
+		assert!(find_next_image(&dir.join("a.png"), false, None, ListingOptions::default()).is_err());
+		assert_eq!(
+			find_next_image(
+				&dir.join("a.png"),
+				false,
+				None,
+				ListingOptions {
+					allow_single: true,
+					..Default::default()
+				}
+			)
+			.unwrap(),
+			dir.join("a.png")
+		);
+	}
+
+	#[test]
+	fn safe_area_panoramic_source_on_portrait_display_stays_in_bounds() {
+		let (img_width, img_height) = (3200, 900);
+		let safe_area = calculate_safe_area(img_width, img_height, &[(1080, 1920)]);
+
+		assert!(safe_area.width > 0 && safe_area.height > 0, "{safe_area:?}");
+		assert!(safe_area.x + safe_area.width <= img_width, "{safe_area:?}");
+		assert!(safe_area.y + safe_area.height <= img_height, "{safe_area:?}");
+	}
+
+	#[test]
+	fn rotated_safe_area_covers_the_same_pixels() {
+		let area = SafeArea {
+			x: 100,
+			y: 50,
+			width: 300,
+			height: 200,
+		};
+		let (width, height) = (1000, 600);
+		for rotation in [0, 90, 180, 270] {
+			let rotated = rotate_safe_area(&area, width, height, rotation);
+			let transform = rotation_transform(rotation, width, height);
+			let mut corners = [
+				tiny_skia::Point::from_xy(rotated.x as f32, rotated.y as f32),
+				tiny_skia::Point::from_xy((rotated.x + rotated.width) as f32, (rotated.y + rotated.height) as f32),
+			];
+			transform.map_points(&mut corners);
+			let (min_x, max_x) = (corners[0].x.min(corners[1].x), corners[0].x.max(corners[1].x));
+			let (min_y, max_y) = (corners[0].y.min(corners[1].y), corners[0].y.max(corners[1].y));
+			assert_eq!((min_x, min_y, max_x, max_y), (100.0, 50.0, 400.0, 250.0), "rotation {rotation}");
+		}
+	}
+
+	#[test]
+	fn png_phys_chunk_is_checksummed() {
+		// Every PNG ends in this exact chunk, CRC included
+		assert_eq!(crc32(b"IEND"), 0xae42_6082);
+
+		let mut png = vec![0u8; 33];
+		insert_png_phys(&mut png, 96);
+		assert_eq!(&png[33..41], &[0, 0, 0, 9, b'p', b'H', b'Y', b's']);
+		assert_eq!(u32::from_be_bytes(png[41..45].try_into().unwrap()), 3780);
+		assert_eq!(png.len(), 33 + 4 + 4 + 9 + 4);
+	}
+
+	#[test]
+	fn wrap_line_breaks_at_spaces_and_splits_long_words() {
+		assert_eq!(wrap_line("short", 10), vec!["short"]);
+		assert_eq!(wrap_line("BTC 1.2345 ETH 20.5", 10), vec!["BTC 1.2345", "ETH 20.5"]);
+		assert_eq!(wrap_line("0x1234567890abcdef", 8), vec!["0x123456", "7890abcd", "ef"]);
+	}
+
+	#[test]
+	fn truncate_lines_wraps_long_lines_before_cutting() {
+		let mut lines = vec!["one two three four five".to_owned()];
+		truncate_lines(&mut lines, 2, 9);
+		assert_eq!(lines, vec!["one two", "three…"]);
+
+		let mut lines = vec!["short".to_owned(), "lines".to_owned()];
+		truncate_lines(&mut lines, 2, 9);
+		assert_eq!(lines, vec!["short", "lines"]);
+	}
+
+	#[test]
+	fn needs_scrim_on_bright_or_busy_backgrounds() {
+		let area = SafeArea { x: 0, y: 0, width: 64, height: 64 };
+		let flat = |v| image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 64, image::Rgb([v, v, v])));
+		let checkerboard = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([if (x + y) % 2 == 0 { 0 } else { 200 }; 3])));
+
+		assert!(!needs_scrim(&flat(20), &area, false));
+		assert!(needs_scrim(&flat(240), &area, false));
+		assert!(!needs_scrim(&flat(240), &area, true));
+		assert!(needs_scrim(&checkerboard, &area, false));
+	}
+
+	#[test]
+	fn thirds_placement_finds_and_moves_into_the_calm_cell() {
+		let area = SafeArea { x: 0, y: 0, width: 90, height: 90 };
+		// Busy everywhere but the bottom-left third
+		let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(90, 90, |x, y| {
+			let calm = x < 30 && y >= 60;
+			image::Rgb([if calm || (x + y) % 2 == 0 { 0 } else { 200 }; 3])
+		}));
+		assert_eq!(calmest_thirds_cell(&img, &area), (0, 2));
+
+		let area = SafeArea {
+			x: 0,
+			y: 0,
+			width: 900,
+			height: 600,
+		};
+		let block = (700, 15, 885, 100);
+		assert_eq!(thirds_offset(block, (2, 0), &area, 15), (0, 0));
+		assert_eq!(thirds_offset(block, (0, 2), &area, 15), (-685, 500));
+		assert_eq!(thirds_offset(block, (1, 1), &area, 15), (-342, 243));
+	}
+
+	#[test]
+	fn parse_xrandr_reads_active_outputs_and_rotation() {
+		let stdout = "\
+Screen 0: minimum 8 x 8, current 3000 x 1920, maximum 32767 x 32767
+DP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 527mm x 296mm
+   1920x1080     60.00*+  50.00
+eDP-1 connected 1080x1920+1920+0 left (normal left inverted right x axis y axis) 309mm x 174mm
+HDMI-1 disconnected (normal left inverted right x axis y axis)
+DP-2 connected (normal left inverted right x axis y axis)
+";
+		let outputs = parse_xrandr(stdout);
+		let summary: Vec<_> = outputs.iter().map(|o| (o.name.as_str(), o.effective_resolution(), o.rotation())).collect();
+		assert_eq!(
+			summary,
+			vec![("DP-1", Some((1920, 1080)), 0), ("eDP-1", Some((1080, 1920)), 270), ("HDMI-1", None, 0), ("DP-2", None, 0),]
+		);
+	}
+
+	#[test]
+	fn scale_brightness_keeps_alpha() {
+		let mut img = image::RgbaImage::from_pixel(7, 5, image::Rgba([200, 100, 10, 128]));
+		scale_brightness(&mut img, 0.5);
+
+		assert!(img.pixels().all(|p| p.0 == [100, 50, 5, 128]));
+	}
+
+	#[test]
+	fn filename_caption_cleans_up_the_stem() {
+		let path = Path::new("/photos/Ansel_Adams - Moonrise.jpg");
+		let pattern = regex::Regex::new(r"^(.+?) - ").unwrap();
+
+		assert_eq!(filename_caption(path, None).as_deref(), Some("Ansel Adams - Moonrise"));
+		assert_eq!(filename_caption(path, Some(&pattern)).as_deref(), Some("Ansel Adams"));
+		assert_eq!(filename_caption(Path::new("/photos/untitled.jpg"), Some(&pattern)), None);
+	}
+
+	#[test]
+	fn format_number_rounds_and_groups_thousands() {
+		assert_eq!(format_number(12345.6789, Some(2), ","), "12,345.68");
+		assert_eq!(format_number(-1234567.0, Some(0), " "), "-1 234 567");
+		assert_eq!(format_number(999.5, None, ","), "999.5");
+		assert_eq!(format_number(-0.001, Some(2), ","), "0.00");
+	}
+
+	#[test]
+	fn read_playlist_resolves_relative_and_skips_missing() {
+		let dir = image_dir("playlist", &["a.png", "b.png"]);
+		let playlist = dir.join("list.txt");
+		std::fs::write(&playlist, "# favourites\nb.png\n\nmissing.png\na.png\nb.png\n").unwrap();
+
+		assert_eq!(read_playlist(&playlist).unwrap(), vec![dir.join("b.png"), dir.join("a.png"), dir.join("b.png")]);
+	}
+
+	/// Overlay parameters for `text` alone on a `width`x`height` image, everything else at its default
+	fn text_params<'a>(text: &'a str, width: u32, height: u32, safe_area: &'a SafeArea) -> CompositeParams<'a> {
+		CompositeParams {
+			bg_image_path: Path::new(""),
+			text: Some(text),
+			author: None,
+			source: None,
+			balances: &[],
+			width,
+			height,
+			safe_area,
+			text_padding: 15,
+			quote_max_lines: None,
+			line_spacing: 1.2,
+			tab_width: 4,
+			font_dirs: &[],
+			text_color: "white",
+			quote_color: None,
+			scrim_color: None,
+			opacity: TextOpacity {
+				quote: 1.0,
+				author: 1.0,
+				balance: 1.0,
+			},
+			rotation: 0,
+			extra_quotes: &[],
+			timestamp: None,
+			caption: None,
+			overlay_image: None,
+			max_overlay_fraction: None,
+			quote_marks: None,
+			antialiasing: Antialiasing::default(),
+			render_scale: 1.0,
+			block_cell: None,
+		}
+	}
+
+	#[test]
+	fn overlong_quote_starts_left_of_the_image() {
+		let safe_area = calculate_safe_area(1920, 1080, &[(1920, 1080)]);
+		let text = "x".repeat(400);
+		let (_, layout) = generate_text_svg(&text_params(&text, 1920, 1080, &safe_area)).unwrap();
+
+		// 400 chars at 16px each, right-aligned 15px in from the right edge
+		assert_eq!(layout.quote.0, 1920 - 15 - 400 * 16);
+	}
+
+	#[test]
+	fn safe_area_never_starts_past_its_end() {
+		let (img_width, img_height) = (3200, 900);
+		let safe_area = calculate_safe_area(img_width, img_height, &[(1080, 1920), (5120, 1440), (1920, 1080)]);
+
+		assert!(safe_area.x + safe_area.width <= img_width, "{safe_area:?}");
+		assert!(safe_area.y + safe_area.height <= img_height, "{safe_area:?}");
+	}
+
+	#[test]
+	fn collapsed_safe_area_still_renders() {
+		// What disjoint crop windows collapse to: nothing, at the right edge of one of them
+		let safe_area = SafeArea {
+			x: 800,
+			y: 0,
+			width: 0,
+			height: 600,
+		};
+		let (svg, layout) = generate_text_svg(&text_params("Disjoint", 800, 600, &safe_area)).unwrap();
+		assert_eq!(layout.quote.0, 800 - 15 - 8 * 16);
+
+		let tree = usvg::Tree::from_str(&svg, &usvg_options(&[], Antialiasing::default())).unwrap();
+		let mut pixmap = tiny_skia::Pixmap::new(800, 600).unwrap();
+		resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+	}
+
+	#[test]
+	fn corner_quote_wraps_within_its_half_and_keeps_to_max_lines() {
+		let safe_area = calculate_safe_area(1920, 1080, &[(1920, 1080)]);
+		let text = "word ".repeat(100);
+		let block = QuoteBlock {
+			text: Some(&text),
+			author: None,
+			color: None,
+		};
+		let params = CompositeParams {
+			quote_max_lines: Some(3),
+			quote_marks: Some(('“', '”')),
+			..text_params("", 1920, 1080, &safe_area)
+		};
+		let (svg, anchor) = corner_quote_svg(&block, Corner::BottomLeft, &params, [15, 7, 3, 1, 0], (28, 21));
+
+		assert_eq!(anchor.0, 15);
+		let lines: Vec<&str> = svg
+			.split("<tspan")
+			.skip(1)
+			.map(|tspan| &tspan[tspan.find('>').unwrap() + 1..tspan.find("</tspan>").unwrap()])
+			.collect();
+		assert_eq!(lines.len(), 3);
+		// Half of 1920, less the padding on both sides, at 16px a char
+		assert!(lines.iter().all(|line| line.chars().count() <= (960 - 30) / 16), "{lines:?}");
+		assert!(lines[0].starts_with('“') && lines[2].ends_with("…”"), "{lines:?}");
+	}
+}